@@ -0,0 +1,45 @@
+//! Compares the interpreter with the compiled rules, run with `cargo bench`.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use rule::{Rule, json};
+
+/// Runs `f` repeatedly for about a second, returns the average time per run.
+fn bench<F: FnMut() -> bool>(mut f: F) -> Duration {
+    let mut runs = 0u32;
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(1) {
+        for _ in 0..1000 {
+            black_box(f());
+        }
+        runs += 1000;
+    }
+    start.elapsed() / runs
+}
+
+fn main() {
+    let event = json!({
+        "user": {"id": 42, "country": "DE", "email": "Tom@Example.com", "tags": ["vip", "beta"]},
+        "amount": 129.5,
+        "items": [{"sku": "A1", "qty": 2}, {"sku": "B7", "qty": 1}],
+        "device": "ios",
+        "ts": 1_700_000_000,
+    });
+    let rules = [
+        ("simple", Rule::parse_expr("amount > 100").unwrap()),
+        ("logic", Rule::parse_expr("amount > 100 && user.country in ('DE', 'FR', 'NL') && device != 'web'").unwrap()),
+        ("string", Rule::parse_expr("endswith(lower(user.email), '@example.com') && regex(items.0.sku, '^[A-Z][0-9]$')").unwrap()),
+        ("arith", Rule::parse_expr("items.0.qty * 10 + items.1.qty * 5 - 3 >= 20 || ts % 60 = 0").unwrap()),
+        ("lambda", Rule::parse_expr("some(items, $.qty > 1 && $.sku != 'B7') && let({c: user.country}, c = 'DE' || c = 'FR')").unwrap()),
+    ];
+
+    println!("{:<8} {:>14} {:>14} {:>8}", "rule", "interpreter", "compiled", "speedup");
+    for (name, rule) in &rules {
+        let compiled = rule.compile();
+        assert_eq!(rule.matches(&event).unwrap(), compiled.matches(&event).unwrap());
+        let interpreted = bench(|| rule.matches(black_box(&event)).unwrap());
+        let compiled = bench(|| compiled.matches(black_box(&event)).unwrap());
+        println!("{:<8} {:>14?} {:>14?} {:>7.1}x", name, interpreted, compiled, interpreted.as_secs_f64() / compiled.as_secs_f64());
+    }
+}
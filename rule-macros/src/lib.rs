@@ -0,0 +1,308 @@
+//! A `rule!` macro that validates the rule at compile time.
+//!
+//! It takes the same Json array syntax as `rule::rule!`, but checks the operator names
+//! and the number of args of every expression against the built-in operators,
+//! a bad rule fails to compile with an error pointing at the offending operator.
+//! A rule without interpolated Rust expressions is also built at compile time,
+//! so the kinds of the args and the literal patterns, durations and time zones are checked too.
+//! It returns a `Rule` instead of a `Result`, since the rule is known to be valid.
+//!
+//! ```
+//! use rule::json;
+//! use rule_macros::rule;
+//!
+//! let r = rule!["startswith", "name", "abc"];
+//! assert!(r.matches(&json!({"name": "abcdef"})).unwrap());
+//! ```
+//!
+//! Rust expressions are interpolated like `json!` does, they are not checked until runtime,
+//! and the macro panics if they make the rule invalid, like `Rule::new(..).unwrap()`.
+//!
+//! ```compile_fail
+//! use rule_macros::rule;
+//!
+//! // error: no such operator `startwith`, did you mean `startswith`?
+//! let r = rule!["startwith", "name", "abc"];
+//! ```
+//!
+//! ```compile_fail
+//! use rule_macros::rule;
+//!
+//! // error: `lower` takes 1 arg, got 2
+//! let r = rule!["=", ["lower", "name", "x"], "abc"];
+//! ```
+//!
+//! ```compile_fail
+//! use rule_macros::rule;
+//!
+//! // error: `<` expects args of one kind, got number and bool
+//! let r = rule!["<", 1, ["<", 2, 3]];
+//! ```
+
+extern crate proc_macro;
+
+use proc_macro2::{Delimiter, Spacing, Span, TokenStream, TokenTree};
+use quote::quote;
+use serde_json::{Map, Value as Json};
+use syn::{Error, Lit, LitStr};
+
+use rule::Rule;
+use rule::op::OpRegistry;
+
+/// Constructs a `rule::Rule` from a Json array literal, validated at compile time.
+#[proc_macro]
+pub fn rule(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let content = unwrap_brackets(input.into());
+    match check(content.clone(), OpRegistry::builtin()) {
+        Ok(()) => quote!(::rule::Rule::new(::rule::json!([#content])).expect("invalid rule")).into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+/// `rule![[...]]` is the same as `rule![...]`.
+fn unwrap_brackets(input: TokenStream) -> TokenStream {
+    let mut it = input.clone().into_iter();
+    match (it.next(), it.next()) {
+        (Some(TokenTree::Group(g)), None) if g.delimiter() == Delimiter::Bracket => g.stream(),
+        _ => input,
+    }
+}
+
+/// A Json value in the macro input.
+enum Node {
+    Array(Vec<Node>, Span),
+    /// The entries, with `None` keys for the ones which are not string literals.
+    Object(Vec<(Option<String>, Node)>, Span),
+    Str(String, Span),
+    /// Other literals, numbers, `true`, `false` and `null`, `None` if it's not a Json value, e.g. `1u8`.
+    Lit(Option<Json>, Span),
+    /// Interpolated Rust expressions.
+    Other(Span),
+}
+
+impl Node {
+    fn span(&self) -> Span {
+        match self {
+            Node::Array(_, span) | Node::Object(_, span) | Node::Str(_, span) | Node::Lit(_, span) | Node::Other(span) => *span,
+        }
+    }
+
+    /// The Json value, `None` if it contains Rust expressions, which are only known at runtime.
+    fn to_json(&self) -> Option<Json> {
+        match self {
+            Node::Array(items, _) => seq_to_json(items),
+            Node::Object(entries, _) => entries.iter()
+                .map(|(key, val)| Some((key.clone()?, val.to_json()?)))
+                .collect::<Option<Map<_, _>>>()
+                .map(Json::Object),
+            Node::Str(s, _) => Some(Json::String(s.clone())),
+            Node::Lit(val, _) => val.clone(),
+            Node::Other(_) => None,
+        }
+    }
+}
+
+fn seq_to_json(items: &[Node]) -> Option<Json> {
+    items.iter().map(Node::to_json).collect::<Option<Vec<_>>>().map(Json::Array)
+}
+
+/// Validates the content of the top level array.
+fn check(content: TokenStream, registry: &OpRegistry) -> syn::Result<()> {
+    let items = parse_seq(content);
+    check_expr(&items, Span::call_site(), registry)?;
+    // a literal rule is built as it would be at runtime
+    match seq_to_json(&items) {
+        Some(val) => match Rule::with_registry(val, registry) {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                let path = err.path().cloned().unwrap_or_default();
+                // the span tells where it is, not the path
+                let message = match path.0.is_empty() {
+                    true => err.to_string(),
+                    false => err.to_string().replacen(&format!(" at {}", path), "", 1),
+                };
+                Err(Error::new(locate(&items, &path.0, Span::call_site()), message))
+            },
+        },
+        None => Ok(()),
+    }
+}
+
+/// The span of the operator of the expression at `path` from the expression `items`.
+fn locate(items: &[Node], path: &[usize], span: Span) -> Span {
+    match path.split_first() {
+        None => items.first().map_or(span, Node::span),
+        Some((i, rest)) => match items.get(i + 1) {
+            Some(Node::Array(inner, span)) => locate(inner, rest, *span),
+            // the path doesn't tell the entry of an object
+            Some(node) => node.span(),
+            None => span,
+        },
+    }
+}
+
+fn check_expr(items: &[Node], span: Span, registry: &OpRegistry) -> syn::Result<()> {
+    let (name, name_span) = match items.first() {
+        Some(Node::Str(name, name_span)) => (name, *name_span),
+        // the operator is computed at runtime
+        Some(Node::Other(_)) => return Ok(()),
+        Some(node) => return Err(Error::new(node.span(), "the operator of an expression must be a string")),
+        None => return Err(Error::new(span, "an expression must start with an operator")),
+    };
+    let op = registry.get(name).ok_or_else(|| Error::new(name_span, no_such_op(name, registry)))?;
+    op.signature.check_arity(name, items.len() - 1).map_err(|message| Error::new(name_span, message))?;
+    if op.quote {
+        // the args are data
+        return Ok(());
+    }
+    items[1..].iter().try_for_each(|arg| check_arg(arg, registry))
+}
+
+fn check_arg(node: &Node, registry: &OpRegistry) -> syn::Result<()> {
+    match node {
+        Node::Array(items, span) => check_expr(items, *span, registry),
+        Node::Object(entries, _) => entries.iter().try_for_each(|(_, v)| check_arg(v, registry)),
+        _ => Ok(()),
+    }
+}
+
+fn no_such_op(name: &str, registry: &OpRegistry) -> String {
+    let mut names: Vec<&str> = registry.names().collect();
+    names.sort_unstable();
+    let closest = names.into_iter()
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= 2)
+        .min_by_key(|(distance, _)| *distance);
+    match closest {
+        Some((_, candidate)) => format!("no such operator `{}`, did you mean `{}`?", name, candidate),
+        None => format!("no such operator `{}`", name),
+    }
+}
+
+/// The Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cur = row[j + 1];
+            row[j + 1] = if ca == *cb { prev } else { 1 + prev.min(cur).min(row[j]) };
+            prev = cur;
+        }
+    }
+    row[b.len()]
+}
+
+/// Splits `stream` by the top level `sep`, skips the empty parts, e.g. of a trailing comma.
+fn split(stream: TokenStream, sep: char) -> Vec<Vec<TokenTree>> {
+    let mut parts = vec![vec![]];
+    for tt in stream {
+        match &tt {
+            TokenTree::Punct(p) if p.as_char() == sep => parts.push(vec![]),
+            _ => parts.last_mut().unwrap().push(tt),
+        }
+    }
+    parts.retain(|part| !part.is_empty());
+    parts
+}
+
+fn parse_seq(stream: TokenStream) -> Vec<Node> {
+    split(stream, ',').into_iter().map(parse_value).collect()
+}
+
+fn parse_value(tokens: Vec<TokenTree>) -> Node {
+    match tokens.as_slice() {
+        [TokenTree::Group(g)] => match g.delimiter() {
+            Delimiter::Bracket => Node::Array(parse_seq(g.stream()), g.span()),
+            Delimiter::Brace => Node::Object(parse_object(g.stream()), g.span()),
+            // the invisible group of a `$e:expr` passed by another macro
+            Delimiter::None => parse_value(g.stream().into_iter().collect()),
+            Delimiter::Parenthesis => Node::Other(g.span()),
+        },
+        [TokenTree::Literal(lit)] => match syn::parse2::<Lit>(TokenTree::Literal(lit.clone()).into()) {
+            Ok(Lit::Str(s)) => Node::Str(s.value(), lit.span()),
+            Ok(lit) => Node::Lit(number(&lit, false), lit.span()),
+            Err(_) => Node::Lit(None, lit.span()),
+        },
+        [TokenTree::Ident(ident)] if ident == "true" || ident == "false" => Node::Lit(Some(Json::Bool(ident == "true")), ident.span()),
+        [TokenTree::Ident(ident)] if ident == "null" => Node::Lit(Some(Json::Null), ident.span()),
+        [TokenTree::Punct(p), TokenTree::Literal(lit)] if p.as_char() == '-' => {
+            let val = syn::parse2::<Lit>(TokenTree::Literal(lit.clone()).into()).ok().and_then(|lit| number(&lit, true));
+            Node::Lit(val, lit.span())
+        },
+        _ => Node::Other(tokens.first().map_or_else(Span::call_site, TokenTree::span)),
+    }
+}
+
+/// The Json number of an unsuffixed number literal, like `json!` makes of it.
+fn number(lit: &Lit, neg: bool) -> Option<Json> {
+    match lit {
+        Lit::Int(i) if i.suffix().is_empty() => {
+            let i = i.base10_parse::<i64>().ok()?;
+            Some(Json::from(if neg { -i } else { i }))
+        },
+        Lit::Float(f) if f.suffix().is_empty() => {
+            let f = f.base10_parse::<f64>().ok()?;
+            Some(Json::from(if neg { -f } else { f }))
+        },
+        _ => None,
+    }
+}
+
+/// Parses the entries of `{key: value, ...}`.
+fn parse_object(stream: TokenStream) -> Vec<(Option<String>, Node)> {
+    split(stream, ',').into_iter().filter_map(|entry| {
+        // the first `:` which is not a part of `::`
+        let colon = entry.iter().enumerate().position(|(i, tt)| match tt {
+            TokenTree::Punct(p) => p.as_char() == ':' && p.spacing() == Spacing::Alone
+                && !matches!(i.checked_sub(1).map(|j| &entry[j]), Some(TokenTree::Punct(q)) if q.as_char() == ':'),
+            _ => false,
+        })?;
+        let key = match &entry[..colon] {
+            [TokenTree::Literal(lit)] => syn::parse2::<LitStr>(TokenTree::Literal(lit.clone()).into()).ok().map(|s| s.value()),
+            _ => None,
+        };
+        Some((key, parse_value(entry[colon + 1..].to_vec())))
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_str(s: &str) -> std::result::Result<(), String> {
+        check(s.parse().unwrap(), OpRegistry::builtin()).map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn test_check() {
+        assert_eq!(check_str(r#""=", "a", 1"#), Ok(()));
+        assert_eq!(check_str(r#""&&", ["startswith", "name", "ab"], {"x": ["has", "a"]}, x"#), Ok(()));
+        assert_eq!(check_str(r#""lit", ["not", "an", "expr"]"#), Ok(()));
+        assert_eq!(check_str(r#"op, "a", 1"#), Ok(()));
+        assert_eq!(check_str(r#""startwith", "a", "b""#), Err("no such operator `startwith`, did you mean `startswith`?".to_owned()));
+        assert_eq!(check_str(r#""=", ["nope_at_all"], 1"#), Err("no such operator `nope_at_all`".to_owned()));
+        assert_eq!(check_str(r#""=", ["lower"], "a""#), Err("`lower` takes 1 arg, got 0".to_owned()));
+        assert_eq!(check_str(r#""=", {"a": ["regex", "x"]}, 1"#), Err("`regex` takes 2 args, got 1".to_owned()));
+        assert_eq!(check_str(r#""=", ["var", "a", 1, 2], 1"#), Err("`var` takes 1 to 2 args, got 3".to_owned()));
+        assert_eq!(check_str(r#""=", "a""#), Err("`=` takes at least 2 args, got 1".to_owned()));
+        assert_eq!(check_str(r#"1, "a""#), Err("the operator of an expression must be a string".to_owned()));
+        assert_eq!(check_str(""), Err("an expression must start with an operator".to_owned()));
+
+        // a literal rule is built, an interpolated one is not
+        assert_eq!(check_str(r#""<", 1, ["<", 2, 3]"#), Err("`<` expects args of one kind, got number and bool".to_owned()));
+        assert_eq!(check_str(r#""&&", true, ["=", ["lower", -1.5], "a"]"#), Err("`lower` expects string as args[0], got number".to_owned()));
+        assert_eq!(check_str(r#""&&", true, {"a": ["dateadd", "t", "3x"]}"#), Err("invalid duration `3x` in `dateadd`".to_owned()));
+        assert_eq!(check_str(r#""<", x, ["<", 2, 3]"#), Ok(()));
+        assert_eq!(check_str(r#""=", {k: ["lower", 1]}, 1"#), Ok(()));
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("startwith", "startswith"), 1);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+}
@@ -0,0 +1,40 @@
+use rule::{Rule, Result, json};
+use rule_macros::rule;
+
+#[test]
+fn rule_macro() -> Result<()> {
+    let r: Rule = rule!["=", "a", 1];
+    assert_eq!(r, Rule::new(json!(["=", "a", 1]))?);
+    assert_eq!(rule![["=", "a", 1]], r);
+    assert!(r.matches(&json!({"a": 1}))?);
+
+    let r = rule!["&&", ["startswith", "name", "ab"], ["in", "country", "DE", "FR"], ["=", ["lit", [1, 2]], ["split", "1,2", ","]]];
+    assert!(!r.matches(&json!({"name": "abc", "country": "DE"}))?);
+    assert_eq!(r.expr().args().len(), 3);
+    Ok(())
+}
+
+#[test]
+fn rule_macro_interpolation() -> Result<()> {
+    let min_age = 18;
+    let countries = ["DE", "FR"];
+    let r = rule!["&&", [">=", "age", min_age], ["in", "country", countries[0], countries[1]]];
+    assert!(r.matches(&json!({"age": 20, "country": "FR"}))?);
+
+    let op = "<";
+    assert!(rule![op, "age", min_age].matches(&json!({"age": 17}))?);
+    Ok(())
+}
+
+#[test]
+#[should_panic(expected = "invalid rule")]
+fn rule_macro_invalid_at_runtime() {
+    let op = "nope";
+    rule![op, "age", 18];
+}
+
+#[test]
+fn rule_macro_compile_errors() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,5 @@
+use rule_macros::rule;
+
+fn main() {
+    let _ = rule!["=", ["lower", "name", "x"], "abc"];
+}
@@ -0,0 +1,5 @@
+use rule_macros::rule;
+
+fn main() {
+    let _ = rule!["<", ["dateadd", ["now"], "30 days"], "t"];
+}
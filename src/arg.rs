@@ -1,14 +1,27 @@
 use std::fmt;
-use std::convert::Into;
+use std::cmp::Ordering;
+use std::result;
+use std::collections::BTreeMap;
+use std::convert::{Into, TryFrom};
 use std::ops::{Add, Sub, Neg, Mul, Div, Rem};
 
+use chrono::{FixedOffset, SecondsFormat};
+use serde::{Serialize, Serializer};
 use serde_json::value::{Value as Json};
 use serde_json::Map;
 
 use crate::rule::{Expr};
-use crate::error::{Error, Result};
+use crate::op::OpRegistry;
+use crate::path::Path;
+use crate::error::{Error, Location, Result};
 
-/// The argument type. Each argument can be a json primitive type or a `Expr`.
+/// A point in time with the offset from UTC it's displayed in, two are equal if they're the same instant.
+pub type DateTime = chrono::DateTime<FixedOffset>;
+
+/// The argument type. Each argument can be a json primitive type, an array, an object, a datetime or a `Expr`.
+///
+/// There's no Json literal of a datetime, it's written as a RFC 3339 string in a context,
+/// and as `["datetime", "2024-05-06T10:00:00Z"]` in a rule.
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub enum Arg {
     Null,
@@ -17,17 +30,29 @@ pub enum Arg {
     Float(f64),
     String(String),
     Array(Vec<Arg>),
+    Object(BTreeMap<String, Arg>),
+    DateTime(DateTime),
     Expr(Expr),
 }
 
+/// Formats a datetime as RFC 3339, with `Z` for UTC and the fraction of a second only if there's one.
+fn rfc3339(v: &DateTime) -> String {
+    v.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+/// The int result of a checked integer operation, `Arg::Null` on overflow or division by zero.
+fn int(v: Option<i64>) -> Arg {
+    v.map_or(Arg::Null, Arg::Int)
+}
+
 impl Add for Arg {
     type Output = Arg;
 
     fn add(self, other: Arg) -> Arg {
         match self {
             Arg::Null => Arg::Int(other.into()),
-            Arg::Bool(v) => Arg::Int(v as i64 + Into::<i64>::into(other)),
-            Arg::Int(v) => Arg::Int(v + Into::<i64>::into(other)),
+            Arg::Bool(v) => int((v as i64).checked_add(Into::<i64>::into(other))),
+            Arg::Int(v) => int(v.checked_add(Into::<i64>::into(other))),
             Arg::Float(v) => Arg::Float(v + Into::<f64>::into(other)),
             Arg::String(v) => Arg::String(v + &Into::<String>::into(other)),
             _ => Arg::Null,
@@ -41,8 +66,8 @@ impl<'a> Add<&'a Arg> for Arg {
     fn add(self, other: &'a Arg) -> Arg {
         match self {
             Arg::Null => Arg::Int(other.clone().into()),
-            Arg::Bool(v) => Arg::Int(v as i64 + Into::<i64>::into(other.clone())),
-            Arg::Int(v) => Arg::Int(v + Into::<i64>::into(other.clone())),
+            Arg::Bool(v) => int((v as i64).checked_add(Into::<i64>::into(other.clone()))),
+            Arg::Int(v) => int(v.checked_add(Into::<i64>::into(other.clone()))),
             Arg::Float(v) => Arg::Float(v + Into::<f64>::into(other.clone())),
             Arg::String(v) => Arg::String(v + &Into::<String>::into(other.clone())),
             _ => Arg::Null,
@@ -55,11 +80,11 @@ impl Sub for Arg {
 
     fn sub(self, other: Arg) -> Arg {
         match self {
-            Arg::Null => Arg::Int(0i64 - Into::<i64>::into(other)),
-            Arg::Bool(v) => Arg::Int(v as i64 - Into::<i64>::into(other)),
-            Arg::Int(v) => Arg::Int(v - Into::<i64>::into(other)),
+            Arg::Null => int(0i64.checked_sub(Into::<i64>::into(other))),
+            Arg::Bool(v) => int((v as i64).checked_sub(Into::<i64>::into(other))),
+            Arg::Int(v) => int(v.checked_sub(Into::<i64>::into(other))),
             Arg::Float(v) => Arg::Float(v - Into::<f64>::into(other)),
-            Arg::String(ref _v) => Arg::Int(Into::<i64>::into(self) - Into::<i64>::into(other)),
+            Arg::String(ref _v) => int(Into::<i64>::into(self).checked_sub(Into::<i64>::into(other))),
             _ => Arg::Null,
         }
     }
@@ -72,24 +97,24 @@ impl Neg for Arg {
         match self {
             Arg::Null => Arg::Int(0),
             Arg::Bool(v) => Arg::Int(-(v as i64)),
-            Arg::Int(v) => Arg::Int(-v),
+            Arg::Int(v) => int(v.checked_neg()),
             Arg::Float(v) => Arg::Float(-v),
-            Arg::String(ref _v) => Arg::Int(-Into::<i64>::into(self)),
+            Arg::String(ref _v) => int(Into::<i64>::into(self).checked_neg()),
             _ => Arg::Null,
         }
     }
 }
 
-impl<'a> Neg for &'a Arg {
+impl Neg for &Arg {
     type Output = Arg;
 
     fn neg(self) -> Arg {
         match self {
             Arg::Null => Arg::Int(0),
             Arg::Bool(v) => Arg::Int(-(*v as i64)),
-            Arg::Int(v) => Arg::Int(-v),
+            Arg::Int(v) => int(v.checked_neg()),
             Arg::Float(v) => Arg::Float(-v),
-            Arg::String(ref _v) => Arg::Int(-Into::<i64>::into(self.clone())),
+            Arg::String(ref _v) => int(Into::<i64>::into(self.clone()).checked_neg()),
             _ => Arg::Null,
         }
     }
@@ -101,10 +126,10 @@ impl Mul for Arg {
     fn mul(self, rhs: Arg) -> Arg {
         match self {
             Arg::Null => Arg::Int(0),
-            Arg::Bool(v) => Arg::Int((v as i64) * Into::<i64>::into(rhs)),
-            Arg::Int(v) => Arg::Int(v * Into::<i64>::into(rhs)),
+            Arg::Bool(v) => int((v as i64).checked_mul(Into::<i64>::into(rhs))),
+            Arg::Int(v) => int(v.checked_mul(Into::<i64>::into(rhs))),
             Arg::Float(v) => Arg::Float(v * Into::<f64>::into(rhs)),
-            Arg::String(ref _v) => Arg::Int(Into::<i64>::into(self) * Into::<i64>::into(rhs)),
+            Arg::String(ref _v) => int(Into::<i64>::into(self).checked_mul(Into::<i64>::into(rhs))),
             _ => Arg::Null,
         }
     }
@@ -116,10 +141,10 @@ impl Div for Arg {
     fn div(self, rhs: Arg) -> Arg {
         match self {
             Arg::Null => Arg::Int(0),
-            Arg::Bool(v) => Arg::Int((v as i64) / Into::<i64>::into(rhs)),
-            Arg::Int(v) => Arg::Int(v / Into::<i64>::into(rhs)),
+            Arg::Bool(v) => int((v as i64).checked_div(Into::<i64>::into(rhs))),
+            Arg::Int(v) => int(v.checked_div(Into::<i64>::into(rhs))),
             Arg::Float(v) => Arg::Float(v / Into::<f64>::into(rhs)),
-            Arg::String(ref _v) => Arg::Int(Into::<i64>::into(self) / Into::<i64>::into(rhs)),
+            Arg::String(ref _v) => int(Into::<i64>::into(self).checked_div(Into::<i64>::into(rhs))),
             _ => Arg::Null,
         }
     }
@@ -131,37 +156,39 @@ impl Rem for Arg {
     fn rem(self, rhs: Arg) -> Arg {
         match self {
             Arg::Null => Arg::Int(0),
-            Arg::Bool(v) => Arg::Int((v as i64) % Into::<i64>::into(rhs)),
-            Arg::Int(v) => Arg::Int(v % Into::<i64>::into(rhs)),
+            Arg::Bool(v) => int((v as i64).checked_rem(Into::<i64>::into(rhs))),
+            Arg::Int(v) => int(v.checked_rem(Into::<i64>::into(rhs))),
             Arg::Float(v) => Arg::Float(v % Into::<f64>::into(rhs)),
-            Arg::String(ref _v) => Arg::Int(Into::<i64>::into(self) % Into::<i64>::into(rhs)),
+            Arg::String(ref _v) => int(Into::<i64>::into(self).checked_rem(Into::<i64>::into(rhs))),
             _ => Arg::Null,
         }
     }
 }
 
-impl Into<String> for Arg {
-    fn into(self) -> String {
-        match self {
+impl From<Arg> for String {
+    fn from(arg: Arg) -> String {
+        match arg {
             Arg::Null => "".to_owned(),
             Arg::Bool(v) => v.to_string(),
             Arg::Int(v) => v.to_string(),
             Arg::Float(v) => v.to_string(),
             Arg::String(v) => v,
+            Arg::DateTime(v) => rfc3339(&v),
             _ => "".to_owned(),
         }
     }
 }
 
 // should always prefer implementing From over Into
-impl<'a> From<&'a Arg> for String {
-    fn from(arg: &'a Arg) -> Self {
+impl From<&Arg> for String {
+    fn from(arg: &Arg) -> Self {
         match arg {
             Arg::Null => "".to_owned(),
             Arg::Bool(v) => v.to_string(),
             Arg::Int(v) => v.to_string(),
             Arg::Float(v) => v.to_string(),
             Arg::String(v) => v.to_string(),
+            Arg::DateTime(v) => rfc3339(v),
             _ => "".to_owned(),
         }
     }
@@ -173,9 +200,9 @@ impl fmt::Display for Arg {
     }
 }
 
-impl Into<i64> for Arg {
-    fn into(self) -> i64 {
-        match self {
+impl From<Arg> for i64 {
+    fn from(arg: Arg) -> i64 {
+        match arg {
             Arg::Null => 0,
             Arg::Bool(v) => v as i64,
             Arg::Int(v) => v,
@@ -186,9 +213,9 @@ impl Into<i64> for Arg {
     }
 }
 
-impl<'a> Into<i64> for &'a Arg {
-    fn into(self) -> i64 {
-        match self {
+impl From<&Arg> for i64 {
+    fn from(arg: &Arg) -> i64 {
+        match arg {
             Arg::Null => 0,
             Arg::Bool(v) => *v as i64,
             Arg::Int(v) => *v,
@@ -199,9 +226,9 @@ impl<'a> Into<i64> for &'a Arg {
     }
 }
 
-impl Into<f64> for Arg {
-    fn into(self) -> f64 {
-        match self {
+impl From<Arg> for f64 {
+    fn from(arg: Arg) -> f64 {
+        match arg {
             Arg::Null => 0.0,
             Arg::Bool(v) => (v as i64) as f64,
             Arg::Int(v) => v as f64,
@@ -212,33 +239,27 @@ impl Into<f64> for Arg {
     }
 }
 
-impl Into<Option<Expr>> for Arg {
-    fn into(self) -> Option<Expr> {
-        match self {
+impl From<Arg> for Option<Expr> {
+    fn from(arg: Arg) -> Option<Expr> {
+        match arg {
             Arg::Expr(v) => Some(v),
             _ => None,
         }
     }
 }
 
-impl Into<Arg> for Json {
-    fn into(self) -> Arg {
-        match self {
-            Json::Null => Arg::Null,
-            Json::Bool(v) => Arg::Bool(v),
-            Json::Number(v) => { 
-                v.as_i64().map_or_else(|| Arg::Float(v.as_f64().unwrap()), |i| Arg::Int(i))
-            },
-            Json::String(v) => Arg::String(v),
-            Json::Array(v) => Arg::Expr(Expr::from_vec(v).unwrap()),
-            Json::Object(v) => Arg::Expr(Expr::from_vec(v.values().cloned().collect()).unwrap()),
-        }
+/// Parses a Json value in a rule, like `Arg::from_json`.
+impl TryFrom<Json> for Arg {
+    type Error = Error;
+
+    fn try_from(val: Json) -> Result<Arg> {
+        Arg::from_json(val)
     }
 }
 
-impl Into<bool> for Arg {
-    fn into(self) -> bool {
-        match self {
+impl From<Arg> for bool {
+    fn from(arg: Arg) -> bool {
+        match arg {
             Arg::Null => false,
             Arg::Bool(v) => v,
             Arg::Int(v) => v != 0,
@@ -249,9 +270,9 @@ impl Into<bool> for Arg {
     }
 }
 
-impl Into<bool> for &Arg {
-    fn into(self) -> bool {
-        match self {
+impl From<&Arg> for bool {
+    fn from(arg: &Arg) -> bool {
+        match arg {
             Arg::Null => false,
             Arg::Bool(v) => *v,
             Arg::Int(v) => *v != 0,
@@ -271,6 +292,14 @@ impl Arg {
         }
     }
 
+    /// If the `Arg` is an Object, returns the associated map. Returns None otherwise.
+    pub fn as_object(&self) -> Option<&BTreeMap<String, Arg>> {
+        match *self {
+            Arg::Object(ref v) => Some(v),
+            _ => None,
+        }
+    }
+
     /// If the `Arg` is a String, returns the associated String. Returns None otherwise.
     pub fn as_str(&self) -> Option<&str> {
         match *self {
@@ -279,17 +308,72 @@ impl Arg {
         }
     }
 
+    /// If the `Arg` is a DateTime, returns the associated DateTime. Returns None otherwise.
+    pub fn as_datetime(&self) -> Option<&DateTime> {
+        match *self {
+            Arg::DateTime(ref v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// Compares two values for equality as the comparison operators do,
+    /// unlike `==`, an `Int` equals a `Float` of the same value, e.g. `1 == 1.0`,
+    /// also in arrays and objects. Values of different types are not equal.
+    pub fn value_eq(&self, other: &Arg) -> bool {
+        match (self, other) {
+            (Arg::Int(_), Arg::Float(_)) | (Arg::Float(_), Arg::Int(_)) => self.value_cmp(other).ok().flatten() == Some(Ordering::Equal),
+            (Arg::Array(a), Arg::Array(b)) => a.len() == b.len() && a.iter().zip(b).all(|(x, y)| x.value_eq(y)),
+            (Arg::Object(a), Arg::Object(b)) => a.len() == b.len() && a.iter().zip(b).all(|((ka, va), (kb, vb))| ka == kb && va.value_eq(vb)),
+            _ => self == other,
+        }
+    }
+
+    /// Orders two values as the comparison operators do:
+    ///
+    /// - numbers are ordered by value, across `Int` and `Float`, e.g. `1 < 1.5`;
+    /// - strings lexicographically, bools as `false < true`, arrays item by item, datetimes in time;
+    /// - `Null` is unordered to anything but itself, i.e. `Ok(None)`, so is the float NaN;
+    /// - other pairs, e.g. a string and a number or any object, can't be ordered, it's an `Error::TypeError`.
+    pub fn value_cmp(&self, other: &Arg) -> Result<Option<Ordering>> {
+        match (self, other) {
+            (Arg::Int(a), Arg::Int(b)) => Ok(Some(a.cmp(b))),
+            (Arg::Float(a), Arg::Float(b)) => Ok(a.partial_cmp(b)),
+            (Arg::Int(a), Arg::Float(b)) => Ok(cmp_int_float(*a, *b)),
+            (Arg::Float(a), Arg::Int(b)) => Ok(cmp_int_float(*b, *a).map(Ordering::reverse)),
+            (Arg::String(a), Arg::String(b)) => Ok(Some(a.cmp(b))),
+            (Arg::Bool(a), Arg::Bool(b)) => Ok(Some(a.cmp(b))),
+            (Arg::DateTime(a), Arg::DateTime(b)) => Ok(Some(a.cmp(b))),
+            (Arg::Null, Arg::Null) => Ok(Some(Ordering::Equal)),
+            (Arg::Null, _) | (_, Arg::Null) => Ok(None),
+            (Arg::Array(a), Arg::Array(b)) => {
+                for (x, y) in a.iter().zip(b) {
+                    match x.value_cmp(y)? {
+                        Some(Ordering::Equal) => continue,
+                        ord => return Ok(ord),
+                    }
+                }
+                Ok(Some(a.len().cmp(&b.len())))
+            },
+            _ => Err(Error::type_error()),
+        }
+    }
+
     // Json::Array => Arg::Expr
     pub fn from_json(val: Json) -> Result<Arg> {
+        Arg::from_json_in(val, OpRegistry::builtin())
+    }
+
+    // Json::Array => Arg::Expr, Json::Object => Arg::Object, resolves the operators in `registry`
+    pub fn from_json_in(val: Json, registry: &OpRegistry) -> Result<Arg> {
         match val {
             Json::Null => Ok(Arg::Null),
             Json::Bool(v) => Ok(Arg::Bool(v)),
             Json::Number(v) => {
-                Ok(v.as_i64().map_or_else(|| Arg::Float(v.as_f64().unwrap()), |i| Arg::Int(i)))
+                Ok(v.as_i64().map_or_else(|| Arg::Float(v.as_f64().unwrap()), Arg::Int))
             },
             Json::String(v) => Ok(Arg::String(v)),
-            Json::Array(v) => Ok(Arg::Expr(Expr::from_vec(v)?)),
-            Json::Object(v) => Ok(Arg::Expr(Expr::from_vec(v.values().cloned().collect())?)),
+            Json::Array(v) => Ok(Arg::Expr(Expr::from_vec_in(v, registry)?)),
+            Json::Object(v) => Ok(Arg::Object(v.into_iter().map(|(k, v)| Ok((k, Arg::from_json_in(v, registry)?))).collect::<Result<_>>()?)),
         }
     }
 
@@ -300,16 +384,83 @@ impl Arg {
             Json::Null => Ok(Arg::Null),
             Json::Bool(v) => Ok(Arg::Bool(v)),
             Json::Number(v) => {
-                Ok(v.as_i64().map_or_else(|| Arg::Float(v.as_f64().unwrap()), |i| Arg::Int(i)))
+                Ok(v.as_i64().map_or_else(|| Arg::Float(v.as_f64().unwrap()), Arg::Int))
             },
             Json::String(v) => Ok(Arg::String(v)),
-            Json::Array(v) => Ok(Arg::Array(v.into_iter().map(|v| Self::from_json_context_var(v)).collect::<Result<Vec<_>>>()?)),
-            Json::Object(v) => Self::from_json_context_var(v.values().cloned().collect()),
+            Json::Array(v) => Ok(Arg::Array(v.into_iter().map(Self::from_json_context_var).collect::<Result<Vec<_>>>()?)),
+            Json::Object(v) => Ok(Arg::Object(v.into_iter().map(|(k, v)| Ok((k, Self::from_json_context_var(v)?))).collect::<Result<_>>()?)),
+        }
+    }
+
+    /// Converts a borrowed Json value as data, like `Arg::from_json_context_var` without cloning the Json first.
+    pub(crate) fn from_json_ref(val: &Json) -> Arg {
+        match val {
+            Json::Null => Arg::Null,
+            Json::Bool(v) => Arg::Bool(*v),
+            Json::Number(v) => v.as_i64().map_or_else(|| Arg::Float(v.as_f64().unwrap()), Arg::Int),
+            Json::String(v) => Arg::String(v.clone()),
+            Json::Array(v) => Arg::Array(v.iter().map(Arg::from_json_ref).collect()),
+            Json::Object(v) => Arg::Object(v.iter().map(|(k, v)| (k.clone(), Arg::from_json_ref(v))).collect()),
         }
     }
 
-    pub fn from_context_var(args: &Vec<Arg>, context: &Map<String, Json>) -> Result<Arg> {
-        Arg::from_json_context_var(context.get(args[0].as_str().ok_or(Error::ExprVarArgNotStringError)?)
-            .ok_or(Error::ContextNoSuchVarError)?.clone())
+    /// Converts to the rule Json that `Arg::from_json` parses back,
+    /// an `Arg::Array` is written as `["lit", [...]]` since a Json array in a rule is an `Expr`,
+    /// and an `Arg::DateTime` as `["datetime", "..."]`.
+    pub fn to_json(&self) -> Json {
+        match self {
+            Arg::Array(_) => Json::Array(vec![Json::String("lit".to_owned()), self.to_json_context_var()]),
+            Arg::DateTime(_) => Json::Array(vec![Json::String("datetime".to_owned()), self.to_json_context_var()]),
+            Arg::Object(v) => Json::Object(v.iter().map(|(k, v)| (k.clone(), v.to_json())).collect()),
+            Arg::Expr(v) => v.to_json(),
+            _ => self.to_json_context_var(),
+        }
+    }
+
+    /// Converts to Json as data, the reverse of `Arg::from_json_context_var`.
+    pub fn to_json_context_var(&self) -> Json {
+        match self {
+            Arg::Null => Json::Null,
+            Arg::Bool(v) => Json::Bool(*v),
+            Arg::Int(v) => Json::from(*v),
+            Arg::Float(v) => Json::from(*v),
+            Arg::String(v) => Json::String(v.clone()),
+            Arg::DateTime(v) => Json::String(rfc3339(v)),
+            Arg::Array(v) => Json::Array(v.iter().map(Arg::to_json_context_var).collect()),
+            Arg::Object(v) => Json::Object(v.iter().map(|(k, v)| (k.clone(), v.to_json_context_var())).collect()),
+            Arg::Expr(v) => v.to_json(),
+        }
+    }
+
+    pub fn from_context_var(args: &[Arg], context: &Map<String, Json>) -> Result<Arg> {
+        let name = args.first().ok_or_else(Error::arity_error)?
+            .as_str().ok_or_else(|| Error::ExprVarArgNotStringError { at: Location::default() })?;
+        Arg::from_json_context_var(Path::parse(name).lookup(context)
+            .ok_or_else(|| Error::no_such_var(name))?.clone())
+    }
+}
+
+/// Compares an int with a float exactly, without rounding the int to a float.
+fn cmp_int_float(i: i64, f: f64) -> Option<Ordering> {
+    if f.is_nan() {
+        None
+    } else if f >= 9_223_372_036_854_775_808.0 {
+        // 2^63, above any i64
+        Some(Ordering::Less)
+    } else if f < -9_223_372_036_854_775_808.0 {
+        Some(Ordering::Greater)
+    } else {
+        // the integral part fits in i64, compare it first, then the fraction
+        let t = f.trunc();
+        match i.cmp(&(t as i64)) {
+            Ordering::Equal => 0.0.partial_cmp(&(f - t)),
+            ord => Some(ord),
+        }
+    }
+}
+
+impl Serialize for Arg {
+    fn serialize<S: Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        self.to_json().serialize(serializer)
     }
 }
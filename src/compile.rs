@@ -0,0 +1,688 @@
+//! Rules compiled for fast evaluation, see `Rule::compile`.
+//!
+//! A `CompiledRule` is a tree of nodes built once from the expression:
+//! the variable paths are parsed into a table the nodes refer to by index,
+//! the args of the eager operators are evaluated onto one stack shared by the whole evaluation
+//! instead of a `Vec` per node, and the context is borrowed instead of converted to Json first.
+//!
+//! The nodes evaluate to values borrowed from the rule and the context where they can,
+//! so the literals and the context variables are only copied into an `Arg` when they're passed
+//! to an operator function, which takes `&[Arg]`. The comparisons, `in`, `not`, the logic and
+//! conditional operators, `let`, the lambdas and `has` are evaluated by the nodes themselves,
+//! without copying their args. The local variables of `let` and the lambdas are resolved
+//! to a depth in the scope when the rule is compiled, and linked on the Rust stack when it's evaluated,
+//! the lambdas iterate their collection in place and the comparisons their args one by one,
+//! so the nodes only allocate the values they build, like the arrays of `map` and `filter`.
+//!
+//! The other lazy operators, and `var` or `has` of a computed name, fall back to the interpreter.
+//! It doesn't see the local variables of the compiled nodes, so a `let` or a lambda whose body
+//! has one of them, or a custom operator which may look up the variables, is interpreted as a whole.
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::fmt;
+
+use serde_json::value::{Value as Json};
+
+use crate::arg::Arg;
+use crate::ctx::{EvalCtx, MissingVar, Options};
+use crate::error::{Error, ExprPath, Result, Span};
+use crate::op::{Func, ACCUMULATOR, ELEMENT};
+use crate::path::{self, Path};
+use crate::rule::Expr;
+
+/// A `Rule` compiled for fast evaluation, it gives the same results and errors as the rule.
+///
+/// ```
+/// use ::rule::{Rule, json};
+///
+/// let r = Rule::parse_expr("age > 18 && user.country in ('DE', 'FR')").unwrap().compile();
+/// assert!(r.matches(&json!({"age": 20, "user": {"country": "DE"}})).unwrap());
+/// assert!(!r.matches(&json!({"age": 20, "user": {"country": "US"}})).unwrap());
+///
+/// let r = Rule::parse_expr("some(items, $.qty > 1 && $.sku != 'A1')").unwrap().compile();
+/// assert!(r.matches(&json!({"items": [{"sku": "A1", "qty": 2}, {"sku": "B7", "qty": 3}]})).unwrap());
+/// ```
+#[derive(Clone)]
+pub struct CompiledRule {
+    root: Node,
+    vars: Vec<Var>,
+    sites: Vec<Site>,
+    options: Options,
+}
+
+/// A context variable read by the rule.
+#[derive(Clone)]
+struct Var {
+    name: String,
+    path: Path,
+}
+
+/// Where an operator is in the rule, to locate its errors like the interpreter does.
+#[derive(Clone)]
+struct Site {
+    op: String,
+    span: Option<Span>,
+    path: ExprPath,
+}
+
+impl Site {
+    fn locate(&self, err: Error) -> Error {
+        self.path.0.iter().rev().fold(err.in_expr(&self.op, self.span), |err, i| err.in_arg(*i))
+    }
+}
+
+/// Where a variable of a literal name is looked up.
+#[derive(Clone)]
+enum Ref {
+    /// The context variable `vars[var]`.
+    Context(usize),
+    /// The path `rest` in the local variable `depth` levels out of the innermost one.
+    Local { depth: usize, rest: Vec<String> },
+}
+
+#[derive(Clone, Copy)]
+enum Cmp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    In,
+}
+
+#[derive(Clone, Copy)]
+enum Lambda {
+    Some,
+    Every,
+    None,
+    Filter,
+    Map,
+    Count,
+    Reduce,
+}
+
+#[derive(Clone)]
+enum Node {
+    Lit(Arg),
+    /// A literal string first arg, which is the variable `at` if it exists.
+    Resolve { at: Ref, lit: Arg },
+    /// `["var", name, default]` of a literal name.
+    Var { at: Ref, name: String, default: Option<Box<Node>>, site: usize },
+    /// `["has", name]` of a literal name.
+    Has(Ref),
+    /// `["has", object, key]`.
+    HasKey(Box<Node>, Box<Node>),
+    Object(Vec<(String, Node)>),
+    /// An eager operator, its args are evaluated onto the stack.
+    Call { func: Func, args: Vec<Node>, site: usize },
+    /// A comparison or `in`, the args are compared as they're borrowed.
+    Compare { cmp: Cmp, args: Vec<Node>, site: usize },
+    Not(Box<Node>),
+    And(Vec<Node>),
+    Or(Vec<Node>),
+    /// `if` and `case`, which are the same for the args `cond, value, ..., default`.
+    Case(Vec<Node>),
+    Switch(Box<Node>, Vec<Node>),
+    Coalesce(Vec<Node>),
+    /// `let`, the body is evaluated in a scope of the values.
+    Let(Vec<Node>, Box<Node>),
+    /// A lambda operator, the body is evaluated in a scope of `$acc` and `$` for `reduce`, of `$` for the others.
+    Lambda { kind: Lambda, items: Box<Node>, body: Option<Box<Node>>, init: Option<Box<Node>>, site: usize },
+    /// An expression evaluated by the interpreter.
+    Interp { expr: Expr, site: usize },
+}
+
+/// A value in evaluation, borrowed from the rule or the context where it can be.
+#[derive(Clone)]
+enum Val<'a> {
+    Arg(Cow<'a, Arg>),
+    Json(&'a Json),
+}
+
+impl<'a> Val<'a> {
+    fn owned(arg: Arg) -> Val<'a> {
+        Val::Arg(Cow::Owned(arg))
+    }
+
+    fn into_arg(self) -> Arg {
+        match self {
+            Val::Arg(arg) => arg.into_owned(),
+            Val::Json(val) => Arg::from_json_ref(val),
+        }
+    }
+
+    /// The value as an `Arg`, converted only if it's from the context.
+    fn as_arg(&self) -> Cow<'_, Arg> {
+        match self {
+            Val::Arg(arg) => Cow::Borrowed(arg),
+            Val::Json(val) => Cow::Owned(Arg::from_json_ref(val)),
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Val::Arg(arg) => arg.as_str(),
+            Val::Json(val) => val.as_str(),
+        }
+    }
+
+    fn is_null(&self) -> bool {
+        match self {
+            Val::Arg(arg) => **arg == Arg::Null,
+            Val::Json(val) => val.is_null(),
+        }
+    }
+
+    /// The truth value of the value, like `bool::from(&Arg)`.
+    fn truthy(&self) -> bool {
+        match self {
+            Val::Json(Json::String(s)) => s.is_empty(),
+            Val::Json(Json::Array(_)) | Val::Json(Json::Object(_)) => false,
+            val => bool::from(&*val.as_arg()),
+        }
+    }
+
+    /// Compares like `Arg::value_eq`, the strings without converting them.
+    fn value_eq(&self, other: &Val) -> bool {
+        match (self.as_str(), other.as_str()) {
+            (Some(a), Some(b)) => a == b,
+            (Some(_), None) | (None, Some(_)) => false,
+            (None, None) => self.as_arg().value_eq(&other.as_arg()),
+        }
+    }
+
+    /// Orders like `Arg::value_cmp`, the strings without converting them.
+    fn value_cmp(&self, other: &Val) -> Result<Option<Ordering>> {
+        match (self.as_str(), other.as_str()) {
+            (Some(a), Some(b)) => Ok(Some(a.cmp(b))),
+            _ => self.as_arg().value_cmp(&other.as_arg()),
+        }
+    }
+}
+
+impl Cmp {
+    /// Compares the next arg `val` with the previous one, with the first one for `in`,
+    /// returns the result of the whole comparison once it's decided.
+    fn step(self, prev: &Val, val: &Val) -> Option<Result<bool>> {
+        let ordered = |pred: fn(Ordering) -> bool| match prev.value_cmp(val) {
+            Ok(ord) if ord.is_some_and(pred) => None,
+            Ok(_) => Some(Ok(false)),
+            Err(err) => Some(Err(err)),
+        };
+        match self {
+            Cmp::Eq => (!prev.value_eq(val)).then_some(Ok(false)),
+            Cmp::Ne => prev.value_eq(val).then_some(Ok(false)),
+            Cmp::Lt => ordered(Ordering::is_lt),
+            Cmp::Le => ordered(Ordering::is_le),
+            Cmp::Gt => ordered(Ordering::is_gt),
+            Cmp::Ge => ordered(Ordering::is_ge),
+            Cmp::In => prev.value_eq(val).then_some(Ok(true)),
+        }
+    }
+}
+
+/// A local variable of a `let` or a lambda, the innermost one of the scope, linked to the outer ones.
+struct Scope<'a> {
+    val: Val<'a>,
+    parent: Option<&'a Scope<'a>>,
+}
+
+impl<'a> Scope<'a> {
+    fn lookup(scope: Option<&'a Scope<'a>>, depth: usize, rest: &[String]) -> Option<Val<'a>> {
+        let mut scope = scope?;
+        for _ in 0..depth {
+            scope = scope.parent?;
+        }
+        match &scope.val {
+            Val::Arg(arg) => path::lookup_arg(rest, arg).map(|arg| Val::Arg(Cow::Borrowed(arg))),
+            Val::Json(val) => path::lookup_json(rest, val).map(Val::Json),
+        }
+    }
+}
+
+/// The elements of a collection, borrowed from it one by one.
+enum Items<'v> {
+    Json(std::slice::Iter<'v, Json>),
+    Arg(std::slice::Iter<'v, Arg>),
+}
+
+impl<'v> Items<'v> {
+    /// The elements of `val`, null is an empty collection, `None` if it's not an array.
+    fn of(val: &'v Val) -> Option<Items<'v>> {
+        match val {
+            Val::Json(Json::Array(items)) => Some(Items::Json(items.iter())),
+            Val::Json(Json::Null) => Some(Items::Arg([].iter())),
+            Val::Arg(arg) => match &**arg {
+                Arg::Array(items) => Some(Items::Arg(items.iter())),
+                Arg::Null => Some(Items::Arg([].iter())),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Items::Json(items) => items.len(),
+            Items::Arg(items) => items.len(),
+        }
+    }
+}
+
+impl<'v> Iterator for Items<'v> {
+    type Item = Val<'v>;
+
+    fn next(&mut self) -> Option<Val<'v>> {
+        match self {
+            Items::Json(items) => items.next().map(Val::Json),
+            Items::Arg(items) => items.next().map(|item| Val::Arg(Cow::Borrowed(item))),
+        }
+    }
+}
+
+impl CompiledRule {
+    pub(crate) fn new(expr: &Expr, options: &Options) -> CompiledRule {
+        let mut compiler = Compiler { vars: vec![], sites: vec![], path: vec![], scope: vec![], escapes: false, strict: options.strict };
+        let root = compiler.expr(expr);
+        CompiledRule { root, vars: compiler.vars, sites: compiler.sites, options: options.clone() }
+    }
+
+    /// Matches the rule with a context.
+    pub fn matches(&self, context: &Json) -> Result<bool> {
+        self.eval(context)?.as_bool().ok_or(Error::FinalResultNotBoolError)
+    }
+
+    /// Evaluates the rule with a context and returns its value as Json, see `Rule::evaluate`.
+    pub fn evaluate(&self, context: &Json) -> Result<Json> {
+        Ok(self.eval(context)?.to_json_context_var())
+    }
+
+    /// Evaluates the rule with a context.
+    pub fn eval(&self, context: &Json) -> Result<Arg> {
+        let ctx = EvalCtx::new(context.as_object().ok_or(Error::ContextNotDictError)?).with_options(&self.options);
+        let mut stack = Vec::with_capacity(16);
+        Ok(self.eval_node(&self.root, &ctx, None, &mut stack)?.into_arg())
+    }
+
+    fn lookup<'a>(&'a self, at: &'a Ref, ctx: &EvalCtx<'a>, scope: Option<&'a Scope<'a>>) -> Option<Val<'a>> {
+        match at {
+            Ref::Context(var) => self.vars[*var].path.lookup(ctx.context()).map(Val::Json),
+            Ref::Local { depth, rest } => Scope::lookup(scope, *depth, rest),
+        }
+    }
+
+    fn eval_node<'a>(&'a self, node: &'a Node, ctx: &EvalCtx<'a>, scope: Option<&'a Scope<'a>>, stack: &mut Vec<Arg>) -> Result<Val<'a>> {
+        match node {
+            Node::Lit(arg) => Ok(Val::Arg(Cow::Borrowed(arg))),
+            Node::Resolve { at, lit } => Ok(self.lookup(at, ctx, scope).unwrap_or(Val::Arg(Cow::Borrowed(lit)))),
+            Node::Var { at, name, default, site } => match self.lookup(at, ctx, scope) {
+                Some(val) => Ok(val),
+                None => match default {
+                    Some(default) => self.eval_node(default, ctx, scope, stack),
+                    None => match &self.options.missing_var {
+                        MissingVar::Error => Err(self.sites[*site].locate(Error::no_such_var(name))),
+                        MissingVar::Null => Ok(Val::owned(Arg::Null)),
+                        MissingVar::Default(val) => Ok(Val::Arg(Cow::Borrowed(val))),
+                    },
+                },
+            },
+            Node::Has(at) => Ok(Val::owned(Arg::Bool(self.lookup(at, ctx, scope).is_some()))),
+            Node::HasKey(obj, key) => {
+                let obj = self.eval_node(obj, ctx, scope, stack)?;
+                let key = self.eval_node(key, ctx, scope, stack)?;
+                let has = match &obj {
+                    Val::Json(Json::Object(obj)) => obj.contains_key(&String::from(&*key.as_arg())),
+                    Val::Arg(arg) => match &**arg {
+                        Arg::Object(obj) => obj.contains_key(&String::from(&*key.as_arg())),
+                        _ => false,
+                    },
+                    _ => false,
+                };
+                Ok(Val::owned(Arg::Bool(has)))
+            },
+            Node::Object(entries) => Ok(Val::owned(Arg::Object(entries.iter()
+                .map(|(k, v)| Ok((k.clone(), self.eval_node(v, ctx, scope, stack)?.into_arg())))
+                .collect::<Result<_>>()?))),
+            Node::Call { func, args, site } => {
+                let base = stack.len();
+                for arg in args {
+                    let val = self.eval_node(arg, ctx, scope, stack)?.into_arg();
+                    stack.push(val);
+                }
+                let result = func(&stack[base..], ctx);
+                stack.truncate(base);
+                result.map(Val::owned).map_err(|err| self.sites[*site].locate(err))
+            },
+            Node::Compare { cmp, args, site } => {
+                // the args are all evaluated as for the eager operators, an error in any of them comes first,
+                // the comparisons stop at the one which decides the result
+                let mut prev = self.eval_node(&args[0], ctx, scope, stack)?;
+                let mut result = None;
+                for arg in &args[1..] {
+                    let val = self.eval_node(arg, ctx, scope, stack)?;
+                    if result.is_none() {
+                        result = cmp.step(&prev, &val);
+                    }
+                    if !matches!(cmp, Cmp::In) {
+                        prev = val;
+                    }
+                }
+                let result = result.unwrap_or(Ok(!matches!(cmp, Cmp::In)));
+                result.map(|v| Val::owned(Arg::Bool(v))).map_err(|err| self.sites[*site].locate(err))
+            },
+            Node::Not(arg) => Ok(Val::owned(Arg::Bool(!self.eval_node(arg, ctx, scope, stack)?.truthy()))),
+            Node::And(args) => {
+                for arg in args {
+                    if !self.eval_node(arg, ctx, scope, stack)?.truthy() {
+                        return Ok(Val::owned(Arg::Bool(false)));
+                    }
+                }
+                Ok(Val::owned(Arg::Bool(true)))
+            },
+            Node::Or(args) => {
+                for arg in args {
+                    if self.eval_node(arg, ctx, scope, stack)?.truthy() {
+                        return Ok(Val::owned(Arg::Bool(true)));
+                    }
+                }
+                Ok(Val::owned(Arg::Bool(false)))
+            },
+            Node::Case(args) => {
+                for pair in args.chunks(2) {
+                    match pair {
+                        [cond, val] => if self.eval_node(cond, ctx, scope, stack)?.truthy() {
+                            return self.eval_node(val, ctx, scope, stack);
+                        },
+                        [default] => return self.eval_node(default, ctx, scope, stack),
+                        _ => unreachable!(),
+                    }
+                }
+                Ok(Val::owned(Arg::Null))
+            },
+            Node::Switch(val, args) => {
+                let val = self.eval_node(val, ctx, scope, stack)?;
+                for pair in args.chunks(2) {
+                    match pair {
+                        [case, res] => if self.eval_node(case, ctx, scope, stack)?.value_eq(&val) {
+                            return self.eval_node(res, ctx, scope, stack);
+                        },
+                        [default] => return self.eval_node(default, ctx, scope, stack),
+                        _ => unreachable!(),
+                    }
+                }
+                Ok(Val::owned(Arg::Null))
+            },
+            Node::Coalesce(args) => {
+                for arg in args {
+                    let val = self.eval_node(arg, ctx, scope, stack)?;
+                    if !val.is_null() {
+                        return Ok(val);
+                    }
+                }
+                Ok(Val::owned(Arg::Null))
+            },
+            Node::Let(vals, body) => Ok(Val::owned(self.bind_all(vals, body, ctx, scope, scope, stack)?)),
+            Node::Lambda { kind, items: coll, body, init, site } => {
+                let coll = self.eval_node(coll, ctx, scope, stack)?;
+                let items = Items::of(&coll).ok_or_else(|| self.sites[*site].locate(Error::type_error()))?;
+                let body = match (kind, body) {
+                    (Lambda::Count, None) => return Ok(Val::owned(Arg::Int(items.len() as i64))),
+                    (_, Some(body)) => body,
+                    (_, None) => unreachable!(),
+                };
+                let truthy = |val: Val| val.truthy();
+                let result = match kind {
+                    Lambda::Some | Lambda::None => {
+                        let mut found = false;
+                        for item in items {
+                            if self.apply(body, item, ctx, scope, stack, truthy)? {
+                                found = true;
+                                break;
+                            }
+                        }
+                        Arg::Bool(found == matches!(kind, Lambda::Some))
+                    },
+                    Lambda::Every => {
+                        for item in items {
+                            if !self.apply(body, item, ctx, scope, stack, truthy)? {
+                                return Ok(Val::owned(Arg::Bool(false)));
+                            }
+                        }
+                        Arg::Bool(true)
+                    },
+                    Lambda::Filter => {
+                        let mut kept = vec![];
+                        for item in items {
+                            if self.apply(body, item.clone(), ctx, scope, stack, truthy)? {
+                                kept.push(item.into_arg());
+                            }
+                        }
+                        Arg::Array(kept)
+                    },
+                    Lambda::Map => Arg::Array(items
+                        .map(|item| self.apply(body, item, ctx, scope, stack, |val| val.into_arg()))
+                        .collect::<Result<_>>()?),
+                    Lambda::Count => {
+                        let mut n = 0;
+                        for item in items {
+                            if self.apply(body, item, ctx, scope, stack, truthy)? {
+                                n += 1;
+                            }
+                        }
+                        Arg::Int(n)
+                    },
+                    Lambda::Reduce => {
+                        let init = init.as_ref().expect("reduce has an initial value");
+                        let mut acc = self.eval_node(init, ctx, scope, stack)?.into_arg();
+                        for item in items {
+                            let outer = Scope { val: Val::owned(acc), parent: scope };
+                            acc = self.apply(body, item, ctx, Some(&outer), stack, |val| val.into_arg())?;
+                        }
+                        acc
+                    },
+                };
+                Ok(Val::owned(result))
+            },
+            Node::Interp { expr, site } => expr.eval(ctx).map(Val::owned).map_err(|err| self.sites[*site].locate(err)),
+        }
+    }
+
+    /// Evaluates the body of a lambda with `item` bound in the innermost scope, and passes its value to `f`.
+    fn apply<'a, T, F>(&'a self, body: &'a Node, item: Val<'a>, ctx: &EvalCtx<'a>, scope: Option<&'a Scope<'a>>, stack: &mut Vec<Arg>, f: F) -> Result<T>
+    where
+        F: FnOnce(Val) -> T,
+    {
+        let inner = Scope { val: item, parent: scope };
+        Ok(f(self.eval_node(body, ctx, Some(&inner), stack)?))
+    }
+
+    /// Evaluates the values of a `let` in the `outer` scope and binds them in turn, then the body in the innermost scope.
+    fn bind_all<'a>(&'a self, vals: &'a [Node], body: &'a Node, ctx: &EvalCtx<'a>, outer: Option<&'a Scope<'a>>, scope: Option<&'a Scope<'a>>, stack: &mut Vec<Arg>) -> Result<Arg> {
+        match vals.split_first() {
+            Some((val, rest)) => {
+                let inner = Scope { val: self.eval_node(val, ctx, outer, stack)?, parent: scope };
+                self.bind_all(rest, body, ctx, outer, Some(&inner), stack)
+            },
+            None => Ok(self.eval_node(body, ctx, scope, stack)?.into_arg()),
+        }
+    }
+}
+
+impl fmt::Debug for CompiledRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompiledRule")
+            .field("vars", &self.vars.iter().map(|var| &var.name).collect::<Vec<_>>())
+            .field("options", &self.options)
+            .finish_non_exhaustive()
+    }
+}
+
+struct Compiler {
+    vars: Vec<Var>,
+    sites: Vec<Site>,
+    path: Vec<usize>,
+    /// The names of the local variables in scope, innermost last.
+    scope: Vec<String>,
+    /// Whether a node in scope falls back to the interpreter, which doesn't see the local variables.
+    escapes: bool,
+    strict: bool,
+}
+
+impl Compiler {
+    /// The variable `name`, a local one if its root is bound in scope, like `EvalCtx::lookup`.
+    fn var(&mut self, name: &str) -> Ref {
+        let path = Path::parse(name);
+        if let Some((root, rest)) = path.segments().split_first() {
+            if let Some(depth) = self.scope.iter().rev().position(|name| name == root) {
+                return Ref::Local { depth, rest: rest.to_vec() };
+            }
+        }
+        match self.vars.iter().position(|var| var.name == name) {
+            Some(i) => Ref::Context(i),
+            None => {
+                self.vars.push(Var { name: name.to_owned(), path });
+                Ref::Context(self.vars.len() - 1)
+            },
+        }
+    }
+
+    fn site(&mut self, expr: &Expr) -> usize {
+        self.sites.push(Site { op: expr.op().name.clone(), span: expr.span(), path: ExprPath(self.path.clone()) });
+        self.sites.len() - 1
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Node {
+        let op = expr.op();
+        let args = expr.args();
+        let compare = match op.builtin {
+            Some("eq") => Some(Cmp::Eq),
+            Some("ne") => Some(Cmp::Ne),
+            Some("lt") => Some(Cmp::Lt),
+            Some("le") => Some(Cmp::Le),
+            Some("gt") => Some(Cmp::Gt),
+            Some("ge") => Some(Cmp::Ge),
+            Some("in") if !args.is_empty() => Some(Cmp::In),
+            _ => None,
+        };
+        if let Some(cmp) = compare {
+            return Node::Compare { cmp, args: self.args(args), site: self.site(expr) };
+        }
+        let lambda = match op.builtin {
+            Some("some") => Some(Lambda::Some),
+            Some("every") => Some(Lambda::Every),
+            Some("none") => Some(Lambda::None),
+            Some("filter") => Some(Lambda::Filter),
+            Some("map") => Some(Lambda::Map),
+            Some("count") => Some(Lambda::Count),
+            Some("reduce") => Some(Lambda::Reduce),
+            _ => None,
+        };
+        match (op.builtin, args, lambda) {
+            (Some("lit"), [arg], _) => Node::Lit(arg.clone()),
+            (Some("var"), [Arg::String(name), default @ ..], _) => {
+                let at = self.var(name);
+                let default = default.first().map(|arg| Box::new(self.arg(1, arg, false)));
+                Node::Var { at, name: name.clone(), default, site: self.site(expr) }
+            },
+            (Some("has"), [Arg::String(name)], _) => Node::Has(self.var(name)),
+            (Some("has"), [_, _], _) => {
+                let mut args = self.args(args).into_iter();
+                Node::HasKey(Box::new(args.next().unwrap()), Box::new(args.next().unwrap()))
+            },
+            (Some("not"), [_], _) => Node::Not(Box::new(self.args(args).remove(0))),
+            (Some("and"), _, _) => Node::And(self.args(args)),
+            (Some("or"), _, _) => Node::Or(self.args(args)),
+            (Some("if"), [_, _], _) | (Some("if"), [_, _, _], _) | (Some("case"), [_, _, ..], _) => Node::Case(self.args(args)),
+            (Some("switch"), [_, _, ..], _) => {
+                let mut args = self.args(args);
+                let val = args.remove(0);
+                Node::Switch(Box::new(val), args)
+            },
+            (Some("coalesce"), _, _) => Node::Coalesce(self.args(args)),
+            (Some("let"), [Arg::Object(vars), body], _) => {
+                self.path.push(0);
+                let vals = vars.values().map(|val| self.value(val, false)).collect();
+                self.path.pop();
+                let names = vars.keys().cloned().collect();
+                match self.scoped(names, |c| c.arg(1, body, false)) {
+                    Some(body) => Node::Let(vals, Box::new(body)),
+                    None => self.interp(expr),
+                }
+            },
+            (_, [coll, rest @ ..], Some(kind)) if rest.len() == matches!(kind, Lambda::Reduce) as usize + 1
+                || matches!(kind, Lambda::Count) && rest.is_empty() => {
+                let items = Box::new(self.arg(0, coll, !self.strict));
+                let site = self.site(expr);
+                let init = match kind {
+                    Lambda::Reduce => Some(Box::new(self.arg(2, &rest[1], false))),
+                    _ => None,
+                };
+                let names = match kind {
+                    Lambda::Reduce => vec![ACCUMULATOR.to_owned(), ELEMENT.to_owned()],
+                    _ => vec![ELEMENT.to_owned()],
+                };
+                let body = match rest.first() {
+                    Some(body) => match self.scoped(names, |c| c.arg(1, body, false)) {
+                        Some(body) => Some(Box::new(body)),
+                        None => return self.interp(expr),
+                    },
+                    None => None,
+                };
+                Node::Lambda { kind, items, body, init, site }
+            },
+            (Some("now"), [], _) => Node::Call { func: op.func.clone(), args: vec![], site: self.site(expr) },
+            _ if op.lazy => self.interp(expr),
+            _ => {
+                // a custom operator is passed the context, it may look up the local variables
+                if op.builtin.is_none() && !self.scope.is_empty() {
+                    self.escapes = true;
+                }
+                Node::Call { func: op.func.clone(), args: self.args(args), site: self.site(expr) }
+            },
+        }
+    }
+
+    fn interp(&mut self, expr: &Expr) -> Node {
+        if !self.scope.is_empty() {
+            self.escapes = true;
+        }
+        Node::Interp { expr: expr.clone(), site: self.site(expr) }
+    }
+
+    /// Compiles the body of a `let` or a lambda with the local variables `names` in scope,
+    /// returns `None` if it has to be interpreted as a whole.
+    fn scoped<F: FnOnce(&mut Compiler) -> Node>(&mut self, names: Vec<String>, body: F) -> Option<Node> {
+        let escapes = std::mem::replace(&mut self.escapes, false);
+        let len = self.scope.len();
+        self.scope.extend(names);
+        let body = body(self);
+        self.scope.truncate(len);
+        if std::mem::replace(&mut self.escapes, escapes) {
+            return None;
+        }
+        Some(body)
+    }
+
+    /// Compiles the args of an operator, the literal string first arg is tried as a context variable.
+    fn args(&mut self, args: &[Arg]) -> Vec<Node> {
+        args.iter().enumerate().map(|(i, arg)| self.arg(i, arg, i == 0 && !self.strict)).collect()
+    }
+
+    fn arg(&mut self, i: usize, arg: &Arg, resolve: bool) -> Node {
+        self.path.push(i);
+        let node = self.value(arg, resolve);
+        self.path.pop();
+        node
+    }
+
+    fn value(&mut self, arg: &Arg, resolve: bool) -> Node {
+        match arg {
+            Arg::String(name) if resolve => Node::Resolve { at: self.var(name), lit: arg.clone() },
+            Arg::Expr(expr) => self.expr(expr),
+            Arg::Object(map) => Node::Object(map.iter().map(|(k, v)| (k.clone(), self.value(v, false))).collect()),
+            _ => Node::Lit(arg.clone()),
+        }
+    }
+}
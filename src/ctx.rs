@@ -0,0 +1,201 @@
+use std::ptr;
+
+use serde_json::value::{Value as Json};
+use serde_json::Map;
+
+use crate::arg::{Arg, DateTime};
+use crate::error::Result;
+use crate::path::{self, Path};
+use crate::rule::Expr;
+use crate::trace::Tracer;
+
+/// What a `var` evaluates to when the variable is missing in the context
+/// and no default value is given like `["var", "x", default]`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MissingVar {
+    /// Fails with `Error::ContextNoSuchVarError`, this is the default.
+    Error,
+    /// Evaluates to `Arg::Null`.
+    Null,
+    /// Evaluates to the given value.
+    Default(Arg),
+}
+
+/// The evaluation options of a `Rule`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    pub missing_var: MissingVar,
+    /// In strict mode, the first argument is never resolved as a context variable implicitly,
+    /// only `var` reads the context.
+    pub strict: bool,
+    /// The time `now` returns, the system clock if `None`, e.g. to test rules at a fixed time.
+    pub now: Option<DateTime>,
+}
+
+static DEFAULT_OPTIONS: Options = Options {
+    missing_var: MissingVar::Error,
+    strict: false,
+    now: None,
+};
+
+impl Default for Options {
+    fn default() -> Options {
+        DEFAULT_OPTIONS.clone()
+    }
+}
+
+/// A variable bound by an operator, e.g. `$` to each element by `some`,
+/// it shadows the outer bindings and the context variables of the same name.
+#[derive(Debug)]
+struct Binding<'a> {
+    name: &'a str,
+    value: &'a Arg,
+    parent: Option<&'a Binding<'a>>,
+}
+
+/// The evaluation context passed to every operator, holds the context the rule is matched with.
+#[derive(Clone, Copy, Debug)]
+pub struct EvalCtx<'a> {
+    context: &'a Map<String, Json>,
+    options: &'a Options,
+    tracer: Option<&'a Tracer>,
+    expr: Option<&'a Expr>,
+    bindings: Option<&'a Binding<'a>>,
+}
+
+impl<'a> EvalCtx<'a> {
+    /// Constructs a new `EvalCtx` from a Json Dict context, with the default options.
+    pub fn new(context: &'a Map<String, Json>) -> EvalCtx<'a> {
+        EvalCtx {
+            context,
+            options: &DEFAULT_OPTIONS,
+            tracer: None,
+            expr: None,
+            bindings: None,
+        }
+    }
+
+    /// Sets the evaluation options.
+    pub fn with_options(self, options: &'a Options) -> EvalCtx<'a> {
+        EvalCtx {
+            options,
+            ..self
+        }
+    }
+
+    /// Records the evaluation trace with `tracer`.
+    pub(crate) fn with_tracer(self, tracer: &'a Tracer) -> EvalCtx<'a> {
+        EvalCtx {
+            tracer: Some(tracer),
+            ..self
+        }
+    }
+
+    pub(crate) fn tracer(&self) -> Option<&'a Tracer> {
+        self.tracer
+    }
+
+    /// The context to evaluate the args of `expr` in.
+    pub(crate) fn with_expr(self, expr: &'a Expr) -> EvalCtx<'a> {
+        EvalCtx {
+            expr: Some(expr),
+            ..self
+        }
+    }
+
+    /// Calls `f` with a context where the variable `name` is bound to `value`,
+    /// it's looked up before the outer bindings and the context, also as the root of a path like `$.price`.
+    ///
+    /// ```
+    /// use ::rule::{Rule, json};
+    /// use ::rule::arg::Arg;
+    /// use ::rule::op::OpRegistry;
+    ///
+    /// // `["with_tax", price, expr]` evaluates `expr` with `$` bound to the price with tax
+    /// let mut registry = OpRegistry::new();
+    /// registry.register_lazy("with_tax", |args, ctx| {
+    ///     let price = Arg::Float(1.2) * ctx.eval(&args[0])?;
+    ///     ctx.bind("$", &price, |ctx| ctx.eval(&args[1]))
+    /// });
+    /// let r = Rule::with_registry(json!(["with_tax", ["var", "price"], [">", ["var", "$"], 100]]), &registry).unwrap();
+    /// assert!(r.matches(&json!({"price": 90})).unwrap());
+    /// ```
+    pub fn bind<T, F>(&self, name: &str, value: &Arg, f: F) -> T
+    where
+        F: FnOnce(&EvalCtx) -> T,
+    {
+        let binding = Binding { name, value, parent: self.bindings };
+        f(&EvalCtx { bindings: Some(&binding), ..*self })
+    }
+
+    /// Returns the Json Dict context the rule is matched with.
+    pub fn context(&self) -> &'a Map<String, Json> {
+        self.context
+    }
+
+    /// Returns the evaluation options.
+    pub fn options(&self) -> &'a Options {
+        self.options
+    }
+
+    /// Evaluates an argument, an `Arg::Expr` is evaluated within this context,
+    /// so are the values of an `Arg::Object`, others are returned as is.
+    pub fn eval(&self, arg: &Arg) -> Result<Arg> {
+        let val = self.eval_arg(arg).map_err(|err| {
+            // locate the error at the arg of the expression being evaluated
+            match self.expr.and_then(|expr| expr.args().iter().position(|a| ptr::eq(a, arg))) {
+                Some(i) => err.in_arg(i),
+                None => err,
+            }
+        })?;
+        if let Some(tracer) = self.tracer {
+            tracer.lazy_arg(&val);
+        }
+        Ok(val)
+    }
+
+    fn eval_arg(&self, arg: &Arg) -> Result<Arg> {
+        match arg {
+            Arg::Expr(expr) => expr.eval(self),
+            Arg::Object(map) => Ok(Arg::Object(map.iter().map(|(k, v)| Ok((k.clone(), self.eval_arg(v)?))).collect::<Result<_>>()?)),
+            _ => Ok(arg.clone()),
+        }
+    }
+
+    /// Looks up a variable by its path, the bound ones first, then the context, returns `None` if not exists.
+    pub fn lookup(&self, name: &str) -> Option<Arg> {
+        let val = self.lookup_path(name);
+        if let Some(tracer) = self.tracer {
+            tracer.var(name, val.as_ref());
+        }
+        val
+    }
+
+    fn lookup_path(&self, name: &str) -> Option<Arg> {
+        let path = Path::parse(name);
+        if let Some((root, rest)) = path.segments().split_first() {
+            let mut binding = self.bindings;
+            while let Some(b) = binding {
+                if root == b.name {
+                    return path::lookup_arg(rest, b.value).cloned();
+                }
+                binding = b.parent;
+            }
+        }
+        path.lookup(self.context).map(Arg::from_json_ref)
+    }
+
+    /// Resolves a literal string first argument as a context variable,
+    /// returns `None` if it's not a string, there's no such variable or in strict mode.
+    pub fn resolve(&self, arg: &Arg) -> Option<Arg> {
+        if self.options.strict {
+            return None;
+        }
+        let name = arg.as_str()?;
+        let val = self.lookup_path(name)?;
+        if let Some(tracer) = self.tracer {
+            tracer.var(name, Some(&val));
+        }
+        Some(val)
+    }
+}
@@ -1,22 +1,231 @@
+use std::error;
+use std::fmt;
 use std::result;
 
+/// The errors of building and evaluating rules.
+///
+/// The errors in evaluation tell where they happened by a `Location`,
+/// the errors in building an `Expr` from Json tell the `ExprPath` of the offending node.
 #[derive(Debug)]
 pub enum Error {
     JsonError(serde_json::Error),
-    ExprIsNotArrayError,
-    ExprOpIsNotStringError,
+    ExprIsNotArrayError { path: ExprPath },
+    ExprOpIsNotStringError { path: ExprPath },
     ExprBuildError,
-    NoSuchOpError,
+    NoSuchOpError { op: String, path: ExprPath },
+    SignatureError { message: String, path: ExprPath },
     ContextNotDictError,
-    ContextNoSuchVarError,
-    ExprVarArgNotStringError,
+    ContextNoSuchVarError { var: String, at: Location },
+    ExprVarArgNotStringError { at: Location },
     FinalResultNotBoolError,
+    InvalidRegex { source: regex::Error, at: Location },
+    InvalidGlob { source: glob::PatternError, at: Location },
+    InvalidDateTime { value: String, at: Location },
+    InvalidDuration { value: String, at: Location },
+    InvalidTimeZone { value: String, at: Location },
+    ArityError { at: Location },
+    TypeError { at: Location },
+    SyntaxError { line: usize, column: usize, message: String },
 
     // MatchError,
 }
 
 pub type Result<T> = result::Result<T, Error>;
 
+/// The path of a node in the rule tree as the arg indices from the root expression,
+/// displayed like `args[2].args[0]`, the root expression itself is the empty path.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ExprPath(pub Vec<usize>);
+
+impl fmt::Display for ExprPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, index) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(".")?;
+            }
+            write!(f, "args[{}]", index)?;
+        }
+        Ok(())
+    }
+}
+
+/// The source span of an expression parsed from the text syntax,
+/// `start` and `end` are byte offsets, `line` and `column` are where it starts, from 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    pub(crate) fn new(src: &str, start: usize, end: usize) -> Span {
+        let before = &src[..start];
+        let line = before.matches('\n').count() + 1;
+        let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+        Span { start, end, line, column }
+    }
+}
+
+/// Where an evaluation error happened: the operator, its path in the rule tree,
+/// and its source span if the rule is parsed from the text syntax.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Location {
+    pub op: String,
+    pub path: ExprPath,
+    pub span: Option<Span>,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "in `{}`", self.op)?;
+        if !self.path.0.is_empty() {
+            write!(f, " at {}", self.path)?;
+        }
+        if let Some(span) = self.span {
+            write!(f, " (line {}, column {})", span.line, span.column)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error {
+    /// An `ArityError` to be returned by an operator, located when it propagates out of the `Expr`.
+    pub fn arity_error() -> Error {
+        Error::ArityError { at: Location::default() }
+    }
+
+    /// A `TypeError` to be returned by an operator, located when it propagates out of the `Expr`.
+    pub fn type_error() -> Error {
+        Error::TypeError { at: Location::default() }
+    }
+
+    /// A `ContextNoSuchVarError` of the variable `var`.
+    pub fn no_such_var(var: &str) -> Error {
+        Error::ContextNoSuchVarError { var: var.to_owned(), at: Location::default() }
+    }
+
+    /// Returns where the error happened, if it's an evaluation error.
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            Error::ContextNoSuchVarError { at, .. }
+            | Error::ExprVarArgNotStringError { at }
+            | Error::InvalidRegex { at, .. }
+            | Error::InvalidGlob { at, .. }
+            | Error::InvalidDateTime { at, .. }
+            | Error::InvalidDuration { at, .. }
+            | Error::InvalidTimeZone { at, .. }
+            | Error::ArityError { at }
+            | Error::TypeError { at } => Some(at),
+            _ => None,
+        }
+    }
+
+    /// Returns the path of the offending node in the rule tree, if it's known.
+    pub fn path(&self) -> Option<&ExprPath> {
+        match self {
+            Error::ExprIsNotArrayError { path }
+            | Error::ExprOpIsNotStringError { path }
+            | Error::NoSuchOpError { path, .. }
+            | Error::SignatureError { path, .. } => Some(path),
+            _ => self.location().map(|at| &at.path),
+        }
+    }
+
+    fn location_mut(&mut self) -> Option<&mut Location> {
+        match self {
+            Error::ContextNoSuchVarError { at, .. }
+            | Error::ExprVarArgNotStringError { at }
+            | Error::InvalidRegex { at, .. }
+            | Error::InvalidGlob { at, .. }
+            | Error::InvalidDateTime { at, .. }
+            | Error::InvalidDuration { at, .. }
+            | Error::InvalidTimeZone { at, .. }
+            | Error::ArityError { at }
+            | Error::TypeError { at } => Some(at),
+            _ => None,
+        }
+    }
+
+    /// Prefixes the path with the arg index `i`, as the error propagates out of `args[i]`.
+    pub(crate) fn in_arg(mut self, i: usize) -> Error {
+        let path = match &mut self {
+            Error::ExprIsNotArrayError { path }
+            | Error::ExprOpIsNotStringError { path }
+            | Error::NoSuchOpError { path, .. }
+            | Error::SignatureError { path, .. } => Some(path),
+            err => err.location_mut().map(|at| &mut at.path),
+        };
+        if let Some(path) = path {
+            path.0.insert(0, i);
+        }
+        self
+    }
+
+    /// Locates the error at the `Expr` it's raised in, the innermost one wins.
+    pub(crate) fn in_expr(mut self, op: &str, span: Option<Span>) -> Error {
+        if let Some(at) = self.location_mut() {
+            if at.op.is_empty() {
+                at.op = op.to_owned();
+                at.span = span;
+            }
+        }
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let at_path = |f: &mut fmt::Formatter, path: &ExprPath| {
+            if path.0.is_empty() { Ok(()) } else { write!(f, " at {}", path) }
+        };
+        match self {
+            Error::JsonError(err) => write!(f, "invalid json: {}", err),
+            Error::ExprIsNotArrayError { path } => {
+                f.write_str("expression is not an array")?;
+                at_path(f, path)
+            },
+            Error::ExprOpIsNotStringError { path } => {
+                f.write_str("operator of expression is not a string")?;
+                at_path(f, path)
+            },
+            Error::ExprBuildError => f.write_str("failed to build expression"),
+            Error::NoSuchOpError { op, path } => {
+                write!(f, "no such operator `{}`", op)?;
+                at_path(f, path)
+            },
+            Error::SignatureError { message, path } => {
+                f.write_str(message)?;
+                at_path(f, path)
+            },
+            Error::ContextNotDictError => f.write_str("context is not a dict"),
+            Error::ContextNoSuchVarError { var, at } => write!(f, "no such context variable `{}` {}", var, at),
+            Error::ExprVarArgNotStringError { at } => write!(f, "variable name is not a string {}", at),
+            Error::FinalResultNotBoolError => f.write_str("final result is not a bool"),
+            Error::InvalidRegex { source, at } => write!(f, "invalid regex {}: {}", at, source),
+            Error::InvalidGlob { source, at } => write!(f, "invalid glob pattern {}: {}", at, source),
+            Error::InvalidDateTime { value, at } => write!(f, "invalid datetime `{}` {}", value, at),
+            Error::InvalidDuration { value, at } => write!(f, "invalid duration `{}` {}", value, at),
+            Error::InvalidTimeZone { value, at } => write!(f, "invalid time zone `{}` {}", value, at),
+            Error::ArityError { at } => write!(f, "wrong number of arguments {}", at),
+            Error::TypeError { at } => write!(f, "invalid argument type {}", at),
+            Error::SyntaxError { line, column, message } => write!(f, "syntax error at line {}, column {}: {}", line, column, message),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::JsonError(err) => Some(err),
+            Error::InvalidRegex { source, .. } => Some(source),
+            Error::InvalidGlob { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
 impl From<serde_json::Error> for Error {
     fn from(err: serde_json::Error) -> Error {
         Error::JsonError(err)
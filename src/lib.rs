@@ -6,7 +6,10 @@
 //! and `arg0..n` is the arguments for the operator. Any argument can be another expression.
 //! 
 //! For writing convenience, the first argument will be tried to resolve as the context parameter.
+//! Use the `lit` operator to keep a literal string, or set `Options::strict` to turn it off for a rule.
 //! Or, you can just use the special `var` operator to indicate the context parameter.
+//! The context parameter can be a dotted path like `user.address.country` or `items.0.sku`,
+//! or a JSON Pointer like `/items/0/sku`, see [`path::Path`](path/struct.Path.html).
 //! 
 //! # Usage
 //! 
@@ -24,15 +27,27 @@
 //!     assert!(Rule::new(json!(["=", ["var", "a"], 1]))?.matches(&context)?);
 //!     assert!(Rule::from_str(r#"["=", ["var", "a"], 1]"#)?.matches(&context)?);
 //!     assert!(Rule::from_value(["=", "world", "hello"])?.matches(&context)?);
+//!
+//!     // nested context parameters
+//!     let nested = json!({"user": {"address": {"country": "DE"}}, "items": [{"sku": "A1"}]});
+//!     assert!(rule!["=", "user.address.country", "DE"]?.matches(&nested)?);
+//!     assert!(rule!["=", ["var", "/items/0/sku"], "A1"]?.matches(&nested)?);
 //! 
 //!     // rule! macro
 //!     assert!(rule!["=", "a", 1]?.matches(&context)?);
 //! 
+//!     // numbers compare by value, ints and floats alike
+//!     assert!(rule!["<", "a", 1.5]?.matches(&context)?);
+//! 
 //!     // collection operators
 //!     assert!(rule!["in", 1, 1, 2, 3]?.matches(&json!({}))?);
 //!     assert!(rule!["startswith", "hello", "he"]?.matches(&json!({}))?);
 //!     assert!(rule!["startswith", "arr", "foo", "bar"]?.matches(&json!({"arr": ["foo", "bar", "baz"]}))?);
 //!     assert!(rule!["endswith", "arr", "bar", "baz"]?.matches(&json!({"arr": ["foo", "bar", "baz"]}))?);
+//!
+//!     // object operators
+//!     assert!(rule!["=", ["get", "user", "name"], "tom"]?.matches(&json!({"user": {"name": "tom"}}))?);
+//!     assert!(rule!["has", "user", "name"]?.matches(&json!({"user": {"name": "tom"}}))?);
 //! 
 //!     Ok(())
 //! }
@@ -51,5 +66,12 @@ pub use self::error::{Error, Result};
 pub mod rule;
 pub mod arg;
 pub mod op;
+pub mod ctx;
+pub mod path;
+pub mod text;
+pub mod trace;
+pub mod types;
+pub mod compile;
+mod optimize;
 pub mod error;
 pub mod macros;
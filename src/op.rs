@@ -1,214 +1,955 @@
-use std::ops::{Add, Sub, Mul, Div, Rem};
+use std::fmt;
+use std::result;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::cmp::Ordering;
+use std::ops::{Add, Sub, Mul, Div, Rem, BitOr};
 use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::{Mutex, PoisonError};
 
+use chrono::{Datelike, FixedOffset, NaiveDate, TimeDelta, Timelike, Utc};
+use chrono_tz::Tz;
 use lazy_static::lazy_static;
+use lru::LruCache;
 
-use crate::arg::Arg;
+use crate::arg::{Arg, DateTime};
+use crate::ctx::{EvalCtx, MissingVar};
+use crate::error::{Error, ExprPath, Location, Result};
 
-pub type Func = fn(Vec<Arg>) -> Arg;
+/// The operator function, it's a closure so that it can capture state, e.g. a lookup table.
+pub type Func = Arc<dyn Fn(&[Arg], &EvalCtx) -> Result<Arg> + Send + Sync>;
 
-/// The Operator type, mainly contains a function pointer.
-#[derive(Clone, Debug, PartialEq, PartialOrd)]
+/// The compile hook of an operator, it's called with the unevaluated args when an expression is built,
+/// and returns a function specialized for them, e.g. with a literal pattern compiled once,
+/// or `None` to keep the generic one.
+pub type Compile = Arc<dyn Fn(&[Arg]) -> Result<Option<Func>> + Send + Sync>;
+
+/// The Operator type, mainly contains a function.
+///
+/// The args of an eager operator are evaluated before the function is called,
+/// while a lazy operator gets its args unevaluated and evaluates them on demand with `EvalCtx::eval`,
+/// e.g. `and` stops evaluating on the first false arg.
+///
+/// The args of a quote operator are parsed as data instead of expressions, e.g. `["lit", [1, 2]]`.
+///
+/// The `signature` tells the number and kinds of args the operator takes, and the kind it returns,
+/// expressions are checked against it when they're built.
+/// An operator with a `compile` hook is specialized for the args of each expression, see `Compile`.
+/// `builtin` is the canonical name of a built-in operator, e.g. `"and"` for `&&`, under whatever name or alias it's registered.
+///
+/// Two `Op`s are compared by name only.
+#[derive(Clone)]
 pub struct Op {
     pub name: String,
     pub func: Func,
+    pub lazy: bool,
+    pub quote: bool,
+    pub signature: Signature,
+    pub compile: Option<Compile>,
+    pub builtin: Option<&'static str>,
+}
+
+/// The number of args an operator takes, `max` is `None` if it's unbounded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Arity {
+    pub min: usize,
+    pub max: Option<usize>,
+}
+
+impl Arity {
+    /// Takes exactly `n` args.
+    pub fn exact(n: usize) -> Arity {
+        Arity { min: n, max: Some(n) }
+    }
+
+    /// Takes `min` args or more.
+    pub fn at_least(min: usize) -> Arity {
+        Arity { min, max: None }
+    }
+
+    /// Takes `min` to `max` args, inclusive.
+    pub fn range(min: usize, max: usize) -> Arity {
+        Arity { min, max: Some(max) }
+    }
+
+    /// Takes any number of args, the arity of a custom operator by default.
+    pub fn any() -> Arity {
+        Arity::at_least(0)
+    }
+
+    /// Returns true if `n` args are accepted.
+    pub fn accepts(&self, n: usize) -> bool {
+        n >= self.min && self.max.is_none_or(|max| n <= max)
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let plural = |n: usize| if n == 1 { "" } else { "s" };
+        match self.max {
+            Some(max) if max == self.min => write!(f, "{} arg{}", max, plural(max)),
+            Some(max) => write!(f, "{} to {} args", self.min, max),
+            None => write!(f, "at least {} arg{}", self.min, plural(self.min)),
+        }
+    }
+}
+
+/// A set of the Json types and datetime, the kinds of values an operator takes or returns.
+///
+/// ```
+/// use ::rule::op::Kind;
+/// let kind = Kind::STRING | Kind::ARRAY;
+/// assert!(kind.contains(Kind::STRING));
+/// assert!(!kind.intersects(Kind::NUMBER));
+/// assert_eq!(kind.to_string(), "string or array");
+/// ```
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Kind(u8);
+
+impl Kind {
+    pub const NULL: Kind = Kind(1);
+    pub const BOOL: Kind = Kind(1 << 1);
+    pub const NUMBER: Kind = Kind(1 << 2);
+    pub const STRING: Kind = Kind(1 << 3);
+    pub const ARRAY: Kind = Kind(1 << 4);
+    pub const OBJECT: Kind = Kind(1 << 5);
+    pub const DATETIME: Kind = Kind(1 << 6);
+    /// Null, bool, number or string, the values that arithmetic coerces.
+    pub const SCALAR: Kind = Kind(0b1111);
+    pub const ANY: Kind = Kind(0b111_1111);
+
+    const NAMES: [(Kind, &'static str); 7] = [
+        (Kind::NULL, "null"),
+        (Kind::BOOL, "bool"),
+        (Kind::NUMBER, "number"),
+        (Kind::STRING, "string"),
+        (Kind::ARRAY, "array"),
+        (Kind::OBJECT, "object"),
+        (Kind::DATETIME, "datetime"),
+    ];
+
+    /// The kind of an arg, an expression is of the kind its operator returns.
+    pub fn of(arg: &Arg) -> Kind {
+        match arg {
+            Arg::Null => Kind::NULL,
+            Arg::Bool(_) => Kind::BOOL,
+            Arg::Int(_) | Arg::Float(_) => Kind::NUMBER,
+            Arg::String(_) => Kind::STRING,
+            Arg::Array(_) => Kind::ARRAY,
+            Arg::Object(_) => Kind::OBJECT,
+            Arg::DateTime(_) => Kind::DATETIME,
+            Arg::Expr(expr) => expr.op().signature.ret,
+        }
+    }
+
+    /// Returns true if all the values of `other` are of this kind.
+    pub fn contains(self, other: Kind) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns true if some values of `other` are of this kind.
+    pub fn intersects(self, other: Kind) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    /// The values of this kind which are not of `other`.
+    pub fn without(self, other: Kind) -> Kind {
+        Kind(self.0 & !other.0)
+    }
+}
+
+impl BitOr for Kind {
+    type Output = Kind;
+
+    fn bitor(self, other: Kind) -> Kind {
+        Kind(self.0 | other.0)
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if *self == Kind::ANY {
+            return f.write_str("any");
+        }
+        let names: Vec<&str> = Kind::NAMES.iter().filter(|(k, _)| self.contains(*k)).map(|(_, name)| *name).collect();
+        match names.split_last() {
+            None => f.write_str("nothing"),
+            Some((last, [])) => f.write_str(last),
+            Some((last, init)) => write!(f, "{} or {}", init.join(", "), last),
+        }
+    }
+}
+
+impl fmt::Debug for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Kind({})", self)
+    }
+}
+
+/// The signature of an operator: the number of args, the kinds of them, and the kind it returns.
+///
+/// It's built up arg by arg, the required args come first, then the optional ones,
+/// then the rest args if the operator is variadic.
+/// A `uniform` operator takes args of one kind besides null, e.g. `+` adds numbers or concatenates strings.
+///
+/// ```
+/// use ::rule::arg::Arg;
+/// use ::rule::op::{Arity, Kind, Signature};
+/// let sig = Signature::new(Kind::ANY).arg(Kind::OBJECT | Kind::ARRAY).arg(Kind::STRING | Kind::NUMBER).optional(Kind::ANY);
+/// assert_eq!(sig.arity, Arity::range(2, 3));
+/// assert_eq!(sig.check("get", &[Arg::Null]), Err("`get` takes 2 to 3 args, got 1".to_owned()));
+/// assert_eq!(sig.check("get", &[Arg::Null, Arg::Bool(true)]), Err("`get` expects array or object as args[0], got null".to_owned()));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Signature {
+    pub arity: Arity,
+    /// The kinds of the leading args.
+    pub args: Vec<Kind>,
+    /// The kind of the args after the leading ones.
+    pub rest: Kind,
+    /// The kind of the value returned.
+    pub ret: Kind,
+    /// Whether the args must be of one kind.
+    pub uniform: bool,
+}
+
+impl Signature {
+    /// A signature without args returning `ret`, add the args with `arg`, `optional` and `rest`.
+    pub fn new(ret: Kind) -> Signature {
+        Signature { arity: Arity::exact(0), args: vec![], rest: Kind::ANY, ret, uniform: false }
+    }
+
+    /// Takes any number of args of any kind, the signature of a custom operator by default.
+    pub fn any() -> Signature {
+        Signature::new(Kind::ANY).rest(Kind::ANY)
+    }
+
+    /// Adds a required arg.
+    pub fn arg(mut self, kind: Kind) -> Signature {
+        self.args.push(kind);
+        self.arity = Arity::exact(self.args.len());
+        self
+    }
+
+    /// Adds an optional arg.
+    pub fn optional(mut self, kind: Kind) -> Signature {
+        self.args.push(kind);
+        self.arity.max = Some(self.args.len());
+        self
+    }
+
+    /// Takes any number of args of `kind` after the leading ones.
+    pub fn rest(mut self, kind: Kind) -> Signature {
+        self.rest = kind;
+        self.arity.max = None;
+        self
+    }
+
+    /// The args must be of one kind besides null.
+    pub fn uniform(mut self) -> Signature {
+        self.uniform = true;
+        self
+    }
+
+    /// The kind expected of `args[i]`.
+    pub fn arg_kind(&self, i: usize) -> Kind {
+        self.args.get(i).copied().unwrap_or(self.rest)
+    }
+
+    /// Checks the number of args of the operator `name`, returns the error message if it's wrong.
+    pub fn check_arity(&self, name: &str, n: usize) -> result::Result<(), String> {
+        if self.arity.accepts(n) {
+            Ok(())
+        } else {
+            Err(format!("`{}` takes {}, got {}", name, self.arity, n))
+        }
+    }
+
+    /// Checks the kind of `args[i]` of the operator `name`, returns the error message if it's wrong.
+    pub fn check_arg(&self, name: &str, i: usize, kind: Kind) -> result::Result<(), String> {
+        let expected = self.arg_kind(i);
+        if expected.intersects(kind) {
+            Ok(())
+        } else {
+            Err(format!("`{}` expects {} as args[{}], got {}", name, expected, i, kind))
+        }
+    }
+
+    /// Checks that the kinds of the args of a uniform operator `name` agree,
+    /// returns the error message if two of them can't be of one kind.
+    pub fn check_uniform(&self, name: &str, kinds: &[Kind]) -> result::Result<(), String> {
+        if !self.uniform {
+            return Ok(());
+        }
+        let kinds: Vec<Kind> = kinds.iter().map(|kind| kind.without(Kind::NULL)).filter(|kind| *kind != Kind(0)).collect();
+        for (i, a) in kinds.iter().enumerate() {
+            if let Some(b) = kinds[i + 1..].iter().find(|b| !a.intersects(**b)) {
+                return Err(format!("`{}` expects args of one kind, got {} and {}", name, a, b));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the args of the operator `name`, returns the error message if they're wrong.
+    ///
+    /// The literal args and the expressions returning a known kind are checked,
+    /// a string as `args[0]` may name a context variable, so it can be of any kind.
+    pub fn check(&self, name: &str, args: &[Arg]) -> result::Result<(), String> {
+        self.check_arity(name, args.len())?;
+        let kinds: Vec<Kind> = args.iter().enumerate().map(|(i, arg)| match arg {
+            Arg::String(_) if i == 0 => Kind::ANY,
+            arg => Kind::of(arg),
+        }).collect();
+        for (i, kind) in kinds.iter().enumerate() {
+            self.check_arg(name, i, *kind)?;
+        }
+        self.check_uniform(name, &kinds)
+    }
 }
 
 // TODO:
 // 1. static hashmap -> built-in ops [done]
-// 2. custom ops register to whom? maybe just use a global static mut hashmap
+// 2. custom ops register to whom? `OpRegistry` [done]
 // 3. add more ops [ing]
 // 4. use macro to init register ops [done]
-// 5. func return Result, error handling? or not.
+// 5. func return Result, error handling? [done]
 
 impl Op {
     /// Constructs a new Operator.
-    pub fn new(name: &str, func: Func) -> Op {
+    pub fn new<F>(name: &str, func: F) -> Op
+    where
+        F: Fn(&[Arg], &EvalCtx) -> Result<Arg> + Send + Sync + 'static,
+    {
         Op {
             name: name.to_owned(),
-            func: func,
+            func: Arc::new(func),
+            lazy: false,
+            quote: false,
+            signature: Signature::any(),
+            compile: None,
+            builtin: None,
         }
     }
 
-    /// Get an Operator by name, returns an Option, `None` if not exists.
+    /// Constructs a new lazy Operator, its args are passed to `func` unevaluated.
+    pub fn new_lazy<F>(name: &str, func: F) -> Op
+    where
+        F: Fn(&[Arg], &EvalCtx) -> Result<Arg> + Send + Sync + 'static,
+    {
+        Op {
+            lazy: true,
+            ..Op::new(name, func)
+        }
+    }
+
+    /// Sets the signature the expressions of the operator are checked against.
+    pub fn with_signature(self, signature: Signature) -> Op {
+        Op {
+            signature,
+            ..self
+        }
+    }
+
+    /// Sets the compile hook which specializes the operator for the args of an expression.
+    pub fn with_compile<F>(self, compile: F) -> Op
+    where
+        F: Fn(&[Arg]) -> Result<Option<Func>> + Send + Sync + 'static,
+    {
+        Op {
+            compile: Some(Arc::new(compile)),
+            ..self
+        }
+    }
+
+    /// Returns the operator specialized for `args` by the compile hook, or a clone of itself without one.
+    pub fn compile(&self, args: &[Arg]) -> Result<Op> {
+        let func = match &self.compile {
+            Some(compile) => compile(args)?,
+            None => None,
+        };
+        Ok(match func {
+            Some(func) => Op { func, ..self.clone() },
+            None => self.clone(),
+        })
+    }
+
+    /// Get a built-in Operator by name, returns an Option, `None` if not exists.
     pub fn get(name: &str) -> Option<&Op> {
         OPS.get(name)
     }
 }
 
-/// Register builtin OPs.
+impl fmt::Debug for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Op")
+            .field("name", &self.name)
+            .field("lazy", &self.lazy)
+            .field("quote", &self.quote)
+            .field("signature", &self.signature)
+            .field("compile", &self.compile.is_some())
+            .field("builtin", &self.builtin)
+            .finish()
+    }
+}
+
+impl PartialEq for Op {
+    fn eq(&self, other: &Op) -> bool {
+        self.name == other.name
+    }
+}
+
+impl PartialOrd for Op {
+    fn partial_cmp(&self, other: &Op) -> Option<Ordering> {
+        self.name.partial_cmp(&other.name)
+    }
+}
+
+/// A set of operators that rules are resolved against when parsed.
 ///
-/// # Examples
+/// `OpRegistry::new()` is pre-seeded with all the built-in operators,
+/// custom operators and aliases can be added on top of them.
+///
+/// ```
+/// use std::collections::HashMap;
+/// use ::rule::{Rule, json};
+/// use ::rule::arg::Arg;
+/// use ::rule::op::OpRegistry;
+///
+/// let countries: HashMap<String, String> = vec![("1.1.1.1".to_owned(), "AU".to_owned())].into_iter().collect();
 ///
+/// let mut registry = OpRegistry::new();
+/// registry.register("is_vip", |args, _| Ok(Arg::Bool(args[0] == Arg::String("vip".to_owned()))));
+/// registry.register("country_of_ip", move |args, _| {
+///     Ok(countries.get(args[0].as_str().unwrap_or("")).map_or(Arg::Null, |c| Arg::String(c.clone())))
+/// });
+/// registry.alias("vip?", "is_vip").unwrap();
+///
+/// let context = json!({"level": "vip", "ip": "1.1.1.1"});
+/// assert!(Rule::with_registry(json!(["vip?", "level"]), &registry).unwrap().matches(&context).unwrap());
+/// assert!(Rule::with_registry(json!(["=", ["country_of_ip", "ip"], "AU"]), &registry).unwrap().matches(&context).unwrap());
 /// ```
+#[derive(Clone, Debug)]
+pub struct OpRegistry {
+    ops: HashMap<String, Op>,
+}
+
+impl OpRegistry {
+    /// Constructs a new registry with all the built-in operators.
+    pub fn new() -> OpRegistry {
+        OPS.clone()
+    }
+
+    /// Constructs a new registry without any operators.
+    pub fn empty() -> OpRegistry {
+        OpRegistry {
+            ops: HashMap::new(),
+        }
+    }
+
+    /// Returns the registry of the built-in operators.
+    pub fn builtin() -> &'static OpRegistry {
+        &OPS
+    }
+
+    /// Registers an operator, replaces the existing one with the same name.
+    pub fn register<F>(&mut self, name: &str, func: F) -> &mut OpRegistry
+    where
+        F: Fn(&[Arg], &EvalCtx) -> Result<Arg> + Send + Sync + 'static,
+    {
+        self.insert(Op::new(name, func))
+    }
+
+    /// Registers a lazy operator, replaces the existing one with the same name.
+    pub fn register_lazy<F>(&mut self, name: &str, func: F) -> &mut OpRegistry
+    where
+        F: Fn(&[Arg], &EvalCtx) -> Result<Arg> + Send + Sync + 'static,
+    {
+        self.insert(Op::new_lazy(name, func))
+    }
+
+    /// Registers an `Op` by its name, replaces the existing one with the same name.
+    pub fn insert(&mut self, op: Op) -> &mut OpRegistry {
+        self.ops.insert(op.name.clone(), op);
+        self
+    }
+
+    /// Registers `alias` as another name of the existing operator `name`.
+    ///
+    /// Returns `Error::NoSuchOpError` if `name` is not registered.
+    pub fn alias(&mut self, alias: &str, name: &str) -> Result<&mut OpRegistry> {
+        let op = self.get(name).ok_or_else(|| Error::NoSuchOpError { op: name.to_owned(), path: ExprPath::default() })?;
+        let op = Op { name: alias.to_owned(), ..op.clone() };
+        Ok(self.insert(op))
+    }
+
+    /// Get an Operator by name, returns an Option, `None` if not exists.
+    pub fn get(&self, name: &str) -> Option<&Op> {
+        self.ops.get(name)
+    }
+
+    /// Returns true if an operator with this name is registered.
+    pub fn contains(&self, name: &str) -> bool {
+        self.ops.contains_key(name)
+    }
+
+    /// Returns the names of all the registered operators, in arbitrary order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.ops.keys().map(String::as_str)
+    }
+}
+
+impl Default for OpRegistry {
+    fn default() -> OpRegistry {
+        OpRegistry::new()
+    }
+}
+
+/// Register builtin OPs, the lazy ones are prefixed with `lazy`, the quote ones with `quote`.
+/// The first name is the canonical one, set as `Op::builtin` whatever the name it's looked up by,
+/// the aliases follow separated by `|`. The signature follows the function name, as the kinds of the args and `-> ret`,
+/// an arg of several kinds is like `string | array`, an optional one is in brackets like `[any]`,
+/// and the variadic rest args come last like `..any`, `where uniform` ends a uniform signature.
+/// A compile hook follows as `with compile_fn`.
+///
+/// # Examples
+///
+/// ```text
 /// register_builtin!(
-///     "var" => var,
-///     "=" => eq,
-///     "<" => lt,
+///     lazy "var" => var(string, [any]) -> any,
+///     quote "lit" | "quote" => lit(any) -> any,
+///     lazy "let" => r#let(object, any) -> any,
+///     "eq" | "=" => eq(any, any, ..any) -> bool,
+///     "add" | "+" | "sum" => add(scalar, ..scalar) -> null | number | string where uniform,
+///     "lower" => lower(string) -> string,
+///     "regex" => regex(string, string) -> bool with compile_regex,
 /// )
 /// ```
 macro_rules! register_builtin {
-    ( $($alias:tt => $func:tt),* $(,)? ) => {
+    ( $($($lazy:ident)? $builtin:literal $(| $alias:literal)* => $func:tt ( $($params:tt)* ) -> $($ret:ident)|+ $(where $flag:ident)? $(with $compile:ident)? ),* $(,)? ) => {
         lazy_static! {
-            /// All built-in OPs registered to OPS HashMap.
-            static ref OPS: HashMap<&'static str, Op> = {
-                let mut map = HashMap::new();
+            /// All built-in OPs registered to OPS registry.
+            static ref OPS: OpRegistry = {
+                let mut registry = OpRegistry::empty();
                 $(
-                map.insert($alias, Op::new($alias, $func as Func));
-                map.insert(stringify!($func), Op::new(stringify!($func), $func as Func));
+                let signature = register_builtin!(@params Signature::new(register_builtin!(@kind $($ret)|+)) ; $($params)*) $(.$flag())?;
+                for name in &[$builtin $(, $alias)*] {
+                    let op = register_builtin!(@op name, $func $(, $lazy)?).with_signature(signature.clone())$(.with_compile($compile))?;
+                    registry.insert(Op { builtin: Some($builtin), ..op });
+                }
                 )*
-                map
+                registry
             };
         }
     };
+    ( @params $sig:expr ; ) => { $sig };
+    ( @params $sig:expr ; .. $($k:ident)|+ ) => { $sig.rest(register_builtin!(@kind $($k)|+)) };
+    ( @params $sig:expr ; [ $($k:ident)|+ ] $(, $($params:tt)*)? ) => {
+        register_builtin!(@params $sig.optional(register_builtin!(@kind $($k)|+)) ; $($($params)*)?)
+    };
+    ( @params $sig:expr ; $($k:ident)|+ $(, $($params:tt)*)? ) => {
+        register_builtin!(@params $sig.arg(register_builtin!(@kind $($k)|+)) ; $($($params)*)?)
+    };
+    ( @kind $($k:ident)|+ ) => { $(register_builtin!(@kind1 $k))|+ };
+    ( @kind1 null ) => { Kind::NULL };
+    ( @kind1 bool ) => { Kind::BOOL };
+    ( @kind1 number ) => { Kind::NUMBER };
+    ( @kind1 string ) => { Kind::STRING };
+    ( @kind1 array ) => { Kind::ARRAY };
+    ( @kind1 object ) => { Kind::OBJECT };
+    ( @kind1 datetime ) => { Kind::DATETIME };
+    ( @kind1 scalar ) => { Kind::SCALAR };
+    ( @kind1 any ) => { Kind::ANY };
+    ( @op $name:expr, $func:tt ) => { Op::new($name, |args, _| $func(args)) };
+    ( @op $name:expr, $func:tt, lazy ) => { Op::new_lazy($name, $func) };
+    ( @op $name:expr, $func:tt, quote ) => { Op { quote: true, ..Op::new_lazy($name, $func) } };
 }
 
 register_builtin!(
-    "var" => var,
+    lazy "var" => var(string, [any]) -> any,
+    quote "lit" | "quote" => lit(any) -> any,
+    lazy "let" => r#let(object, any) -> any,
 
     // logic operator
-    "=" => eq,
-    "<" => lt,
-    "<=" => le,
-    "!=" => ne,
-    ">=" => ge,
-    ">=" => ge,
-    ">" => gt,
-    "&" => and,
-    "&&" => and,
-    "all" => and,
-    "|" => or,
-    "||" => or,
-    "any" => or,
-    "!" => not,
+    "eq" | "=" => eq(any, any, ..any) -> bool,
+    "lt" | "<" => lt(any, any, ..any) -> bool where uniform,
+    "le" | "<=" => le(any, any, ..any) -> bool where uniform,
+    "ne" | "!=" => ne(any, any, ..any) -> bool,
+    "ge" | ">=" => ge(any, any, ..any) -> bool where uniform,
+    "gt" | ">" => gt(any, any, ..any) -> bool where uniform,
+    lazy "and" | "&" | "&&" | "all" => and(any, ..any) -> bool,
+    lazy "or" | "|" | "||" | "any" => or(any, ..any) -> bool,
+    "not" | "!" => not(any) -> bool,
+
+    // conditional operator
+    lazy "if" => r#if(any, any, [any]) -> any,
+    lazy "case" => case(any, any, ..any) -> any,
+    lazy "switch" => switch(any, any, any, ..any) -> any,
+    lazy "coalesce" => coalesce(any, ..any) -> any,
 
     // arithmetic operator
-    "+" => add,
-    "sum" => add,
-    "-" => sub,
-    "minus" => sub,
-    "neg" => neg,
-    "*" => mul,
-    "/" => div,
-    "%" => rem,
-    "mod" => rem,
-    "abs" => abs,
+    "add" | "+" | "sum" => add(scalar, ..scalar) -> null | number | string where uniform,
+    "sub" | "-" | "minus" => sub(scalar, ..scalar) -> null | number,
+    "neg" => neg(scalar) -> null | number,
+    "mul" | "*" => mul(scalar, ..scalar) -> null | number,
+    "div" | "/" => div(scalar, ..scalar) -> null | number,
+    "rem" | "%" | "mod" => rem(scalar, ..scalar) -> null | number,
+    "abs" => abs(scalar) -> null | number,
 
     // collection operator
-    "in" => r#in,
-    "startswith" => startswith,
-    "endswith" => endswith,
-    "split" => split,
-    "join" => join,
+    "in" => r#in(any, ..any) -> bool,
+    "startswith" => startswith(string | array, any, ..any) -> bool,
+    "endswith" => endswith(string | array, any, ..any) -> bool,
+    "split" => split(string, string) -> array,
+    "join" => join(string, ..any) -> string,
+    lazy "some" => some(array | null, any) -> bool,
+    lazy "every" => every(array | null, any) -> bool,
+    lazy "none" => none(array | null, any) -> bool,
+    lazy "filter" => filter(array | null, any) -> array,
+    lazy "map" => map(array | null, any) -> array,
+    lazy "count" => count(array | null, [any]) -> number,
+    lazy "reduce" => reduce(array | null, any, any) -> any,
+
+    // object operator
+    "keys" => keys(object) -> array,
+    "values" => values(object) -> array,
+    lazy "has" => has(string | object, [string]) -> bool,
+    "get" => get(object | array, string | number, [any]) -> any,
 
     // string operator
-    "lower" => lower,
-    "upper" => upper,
-    "match" => r#match,
-    "regex" => regex,
+    "lower" => lower(string) -> string,
+    "upper" => upper(string) -> string,
+    "match" => r#match(string, string) -> bool with compile_glob,
+    "regex" => regex(string, string) -> bool with compile_regex,
+
+    // datetime operator
+    "datetime" => datetime(null | number | string | datetime) -> null | datetime,
+    lazy "now" => now() -> datetime,
+    "dateadd" => dateadd(null | number | string | datetime, number | string) -> null | datetime with compile_duration,
+    "datediff" => datediff(null | number | string | datetime, null | number | string | datetime) -> null | number,
+    "timezone" => timezone(null | number | string | datetime, string) -> null | datetime with compile_timezone,
+    "year" => year(null | number | string | datetime) -> null | number,
+    "month" => month(null | number | string | datetime) -> null | number,
+    "day" => day(null | number | string | datetime) -> null | number,
+    "weekday" => weekday(null | number | string | datetime) -> null | number,
+    "hour" => hour(null | number | string | datetime) -> null | number,
+    "minute" => minute(null | number | string | datetime) -> null | number,
+    "second" => second(null | number | string | datetime) -> null | number,
 
     // casting operator
-    "num" => num,
-    "string" => string,
+    "num" => num(scalar) -> number,
+    "string" => string(any) -> string,
 );
 
-/// just a placeholder, will not be called
-pub fn var(args: Vec<Arg>) -> Arg {
-    args[0].clone()
+/// Returns `args[i]`, or `Error::ArityError` if there are not enough args.
+fn arg(args: &[Arg], i: usize) -> Result<&Arg> {
+    args.get(i).ok_or_else(Error::arity_error)
 }
 
-pub fn eq(args: Vec<Arg>) -> Arg {
-    Arg::Bool(args.windows(2).all(|w| w[0] == w[1]))
+/// Evaluates `args[i]` of a lazy operator, the first arg is tried with context var like the eager ones.
+fn eval_arg(args: &[Arg], i: usize, ctx: &EvalCtx) -> Result<Arg> {
+    let arg = arg(args, i)?;
+    if i == 0 {
+        if let Some(var) = ctx.resolve(arg) {
+            if let Some(tracer) = ctx.tracer() {
+                tracer.lazy_arg(&var);
+            }
+            return Ok(var);
+        }
+    }
+    ctx.eval(arg)
 }
 
-/// `lt` is equivalent to the `<` sign, args[0] and args[1] must be the same type.
+/// Get a context variable by name, args[1] is the default value if the variable is missing.
+/// Without a default value, a missing variable is handled as `Options::missing_var`.
+///
+/// ```
+/// use ::rule::{rule, json};
+/// assert!(rule!["=", ["var", "a"], 1].unwrap().matches(&json!({"a": 1})).unwrap());
+/// assert!(rule!["=", ["var", "b", 2], 2].unwrap().matches(&json!({"a": 1})).unwrap());
+/// assert!(rule!["=", ["var", "b"], 2].unwrap().matches(&json!({"a": 1})).is_err());
+/// ```
+pub fn var(args: &[Arg], ctx: &EvalCtx) -> Result<Arg> {
+    let name = ctx.eval(arg(args, 0)?)?;
+    let name = name.as_str().ok_or_else(|| Error::ExprVarArgNotStringError { at: Location::default() })?;
+    match ctx.lookup(name) {
+        Some(val) => Ok(val),
+        None => match args.get(1) {
+            Some(default) => ctx.eval(default),
+            None => match &ctx.options().missing_var {
+                MissingVar::Error => Err(Error::no_such_var(name)),
+                MissingVar::Null => Ok(Arg::Null),
+                MissingVar::Default(val) => Ok(val.clone()),
+            },
+        },
+    }
+}
+
+/// Returns args[0] as a literal, it's never resolved as a context variable.
+/// Aliases: lit, quote
+///
+/// ```
+/// use ::rule::{rule, json};
+/// assert!(rule!["=", ["lit", "status"], "status"].unwrap().matches(&json!({"status": "active"})).unwrap());
+/// assert!(rule!["startswith", ["lit", [1, 2, 3]], 1, 2].unwrap().matches(&json!({})).unwrap());
+/// ```
+pub fn lit(args: &[Arg], _ctx: &EvalCtx) -> Result<Arg> {
+    Ok(arg(args, 0)?.clone())
+}
+
+/// Evaluates the body args[1] with local variables bound to the values of the object args[0],
+/// they're looked up by `var` before the context. The values are evaluated once, in the outer scope,
+/// so a value can't refer to another variable of the same `let`, nest them for that.
+///
+/// ```
+/// use ::rule::{rule, json};
+/// let r = rule!["let", {"email": ["lower", ["var", "email"]]}, ["||", ["=", "email", "tom@example.com"], ["endswith", "email", "@corp.com"]]].unwrap();
+/// assert!(r.matches(&json!({"email": "Tom@Example.com"})).unwrap());
+/// assert!(rule!["let", {"x": 2}, ["let", {"y": ["*", ["var", "x"], 10]}, ["=", "y", 20]]].unwrap().matches(&json!({})).unwrap());
+/// ```
+pub fn r#let(args: &[Arg], ctx: &EvalCtx) -> Result<Arg> {
+    let vars = match arg(args, 0)? {
+        Arg::Object(map) => map.iter()
+            .map(|(name, val)| Ok((name.as_str(), ctx.eval(val).map_err(|err| err.in_arg(0))?)))
+            .collect::<Result<Vec<_>>>()?,
+        _ => return Err(Error::type_error()),
+    };
+    bind_all(&vars, ctx, arg(args, 1)?)
+}
+
+fn bind_all(vars: &[(&str, Arg)], ctx: &EvalCtx, body: &Arg) -> Result<Arg> {
+    match vars.split_first() {
+        Some(((name, val), rest)) => ctx.bind(name, val, |ctx| bind_all(rest, ctx, body)),
+        None => ctx.eval(body),
+    }
+}
+
+/// `eq` is equivalent to the `=` sign, numbers are equal by value, e.g. `1 = 1.0`,
+/// values of different types are not equal, see `Arg::value_eq`.
+///
+/// ```
+/// use ::rule::{rule, json};
+/// assert!(rule!["eq", 1, 1.0].unwrap().matches(&json!({})).unwrap());
+/// assert!(rule!["eq", "a", 1].unwrap().matches(&json!({"a": 1.0})).unwrap());
+/// assert_eq!(rule!["eq", "1", 1].unwrap().matches(&json!({})).unwrap(), false);
+/// ```
+pub fn eq(args: &[Arg]) -> Result<Arg> {
+    Ok(Arg::Bool(args.windows(2).all(|w| w[0].value_eq(&w[1]))))
+}
+
+/// Returns true if `pred` holds for the ordering of every two adjacent args, see `Arg::value_cmp`.
+/// Unordered args, e.g. a null, are never in order, the args that can't be ordered are a type error.
+fn ordered(args: &[Arg], pred: fn(Ordering) -> bool) -> Result<Arg> {
+    for w in args.windows(2) {
+        if !w[0].value_cmp(&w[1])?.is_some_and(pred) {
+            return Ok(Arg::Bool(false));
+        }
+    }
+    Ok(Arg::Bool(true))
+}
+
+/// `lt` is equivalent to the `<` sign, numbers are ordered by value across ints and floats,
+/// strings, bools and arrays within their own type, see `Arg::value_cmp`.
 ///
 /// ```
 /// use ::rule::{rule, json};
 /// assert!(rule!["lt", 1, 2].unwrap().matches(&json!({})).unwrap());
 /// assert!(rule!["lt", "10", "2"].unwrap().matches(&json!({})).unwrap());
 /// assert!(rule!["lt", 1.1, 1.23].unwrap().matches(&json!({})).unwrap());
+/// assert!(rule!["lt", 1, 1.5].unwrap().matches(&json!({})).unwrap());
 /// assert_eq!(rule!["lt", 1.23, 1.1].unwrap().matches(&json!({})).unwrap(), false);
 /// assert_eq!(rule!["lt", 2, 1].unwrap().matches(&json!({})).unwrap(), false);
+/// assert_eq!(rule!["lt", ["var", "x", null], 1].unwrap().matches(&json!({})).unwrap(), false);
+/// assert!(rule!["lt", "a", 1].unwrap().matches(&json!({"a": "b"})).is_err());
 /// ```
-pub fn lt(args: Vec<Arg>) -> Arg {
-    Arg::Bool(args.windows(2).all(|w| w[0] < w[1]))
+pub fn lt(args: &[Arg]) -> Result<Arg> {
+    ordered(args, Ordering::is_lt)
 }
 
-pub fn le(args: Vec<Arg>) -> Arg {
-    Arg::Bool(args.windows(2).all(|w| w[0] <= w[1]))
+pub fn le(args: &[Arg]) -> Result<Arg> {
+    ordered(args, Ordering::is_le)
 }
 
-pub fn ne(args: Vec<Arg>) -> Arg {
-    Arg::Bool(args.windows(2).all(|w| w[0] != w[1]))
+pub fn ne(args: &[Arg]) -> Result<Arg> {
+    Ok(Arg::Bool(args.windows(2).all(|w| !w[0].value_eq(&w[1]))))
 }
 
-pub fn ge(args: Vec<Arg>) -> Arg {
-    Arg::Bool(args.windows(2).all(|w| w[0] >= w[1]))
+pub fn ge(args: &[Arg]) -> Result<Arg> {
+    ordered(args, Ordering::is_ge)
 }
 
-pub fn gt(args: Vec<Arg>) -> Arg {
-    Arg::Bool(args.windows(2).all(|w| w[0] > w[1]))
+pub fn gt(args: &[Arg]) -> Result<Arg> {
+    ordered(args, Ordering::is_gt)
 }
 
-pub fn and(args: Vec<Arg>) -> Arg {
-    Arg::Bool(args.iter().all(|v| v.into()))
+/// Return true if all args are true, stops evaluating on the first false one.
+/// Aliases: &, &&, all
+///
+/// ```
+/// use ::rule::{rule, json};
+/// assert!(rule!["&", true, ["=", "a", 1]].unwrap().matches(&json!({"a": 1})).unwrap());
+/// assert_eq!(rule!["&", false, ["regex", "a", ["lit", "("]]].unwrap().matches(&json!({"a": 1})).unwrap(), false);
+/// ```
+pub fn and(args: &[Arg], ctx: &EvalCtx) -> Result<Arg> {
+    for i in 0..args.len() {
+        if !Into::<bool>::into(eval_arg(args, i, ctx)?) {
+            return Ok(Arg::Bool(false));
+        }
+    }
+    Ok(Arg::Bool(true))
 }
 
-pub fn or(args: Vec<Arg>) -> Arg {
-    Arg::Bool(args.iter().any(|v| v.into()))
+/// Return true if any arg is true, stops evaluating on the first true one.
+/// Aliases: |, ||, any
+///
+/// ```
+/// use ::rule::{rule, json};
+/// assert!(rule!["|", true, ["regex", "a", ["lit", "("]]].unwrap().matches(&json!({"a": 1})).unwrap());
+/// ```
+pub fn or(args: &[Arg], ctx: &EvalCtx) -> Result<Arg> {
+    for i in 0..args.len() {
+        if eval_arg(args, i, ctx)?.into() {
+            return Ok(Arg::Bool(true));
+        }
+    }
+    Ok(Arg::Bool(false))
 }
 
-pub fn not(args: Vec<Arg>) -> Arg {
-    let b: bool = args.get(0).unwrap_or(&Arg::Null).into();
-    Arg::Bool(!b)
+pub fn not(args: &[Arg]) -> Result<Arg> {
+    let b: bool = args.first().unwrap_or(&Arg::Null).into();
+    Ok(Arg::Bool(!b))
 }
 
-pub fn add(args: Vec<Arg>) -> Arg {
-    let mut it = args.into_iter();
-    it.next().map(|first| it.fold(first, Add::add)).unwrap_or(Arg::Null)
+/// Return args[1] if args[0] is true, else args[2], or null without it.
+/// Only the chosen value is evaluated.
+///
+/// ```
+/// use ::rule::{Rule, rule, json};
+/// let r = rule!["if", [">", "amount", 100], 0.1, 0].unwrap();
+/// assert_eq!(r.evaluate(&json!({"amount": 150})).unwrap(), json!(0.1));
+/// assert_eq!(r.evaluate(&json!({"amount": 50})).unwrap(), json!(0));
+/// assert_eq!(Rule::parse_expr("if(vip, 'priority')").unwrap().evaluate(&json!({"vip": false})).unwrap(), json!(null));
+/// ```
+pub fn r#if(args: &[Arg], ctx: &EvalCtx) -> Result<Arg> {
+    if eval_arg(args, 0, ctx)?.into() {
+        ctx.eval(arg(args, 1)?)
+    } else {
+        args.get(2).map_or(Ok(Arg::Null), |val| ctx.eval(val))
+    }
 }
 
-pub fn sub(args: Vec<Arg>) -> Arg {
-    let mut it = args.into_iter();
-    it.next().map(|first| it.fold(first, Sub::sub)).unwrap_or(Arg::Null)
+/// Return the value after the first true condition of the pairs `cond, value, ...`,
+/// a last unpaired arg is the default, null without it. The conditions are evaluated in order,
+/// and only the chosen value is.
+///
+/// ```
+/// use ::rule::{rule, json};
+/// let r = rule!["case", [">=", "amount", 1000], 0.2, [">=", "amount", 100], 0.1, 0].unwrap();
+/// assert_eq!(r.evaluate(&json!({"amount": 1500})).unwrap(), json!(0.2));
+/// assert_eq!(r.evaluate(&json!({"amount": 150})).unwrap(), json!(0.1));
+/// assert_eq!(r.evaluate(&json!({"amount": 15})).unwrap(), json!(0));
+/// ```
+pub fn case(args: &[Arg], ctx: &EvalCtx) -> Result<Arg> {
+    for i in (0..args.len()).step_by(2) {
+        if i + 1 == args.len() {
+            return ctx.eval(&args[i]);
+        }
+        if eval_arg(args, i, ctx)?.into() {
+            return ctx.eval(&args[i + 1]);
+        }
+    }
+    Ok(Arg::Null)
 }
 
-pub fn neg(args: Vec<Arg>) -> Arg {
-    -args.get(0).unwrap_or(&Arg::Null)
+/// Return the value after the first of the pairs `match, value, ...` that args[0] equals,
+/// by `Arg::value_eq` like `=`, a last unpaired arg is the default, null without it.
+///
+/// ```
+/// use ::rule::{rule, json};
+/// let r = rule!["switch", "country", "DE", "eu-central", "US", "us-east", "default"].unwrap();
+/// assert_eq!(r.evaluate(&json!({"country": "US"})).unwrap(), json!("us-east"));
+/// assert_eq!(r.evaluate(&json!({"country": "JP"})).unwrap(), json!("default"));
+/// ```
+pub fn switch(args: &[Arg], ctx: &EvalCtx) -> Result<Arg> {
+    let val = eval_arg(args, 0, ctx)?;
+    for i in (1..args.len()).step_by(2) {
+        if i + 1 == args.len() {
+            return ctx.eval(&args[i]);
+        }
+        if ctx.eval(&args[i])?.value_eq(&val) {
+            return ctx.eval(&args[i + 1]);
+        }
+    }
+    Ok(Arg::Null)
 }
 
-pub fn mul(args: Vec<Arg>) -> Arg {
-    let mut it = args.into_iter();
-    it.next().map(|first| it.fold(first, Mul::mul)).unwrap_or(Arg::Null)
+/// Return the first arg which is not null, null if all are, stops evaluating on it.
+/// A missing variable is an error unless it has a default like `["var", "x", null]`
+/// or `Options::missing_var` says otherwise.
+///
+/// ```
+/// use ::rule::{rule, json};
+/// let r = rule!["coalesce", ["var", "nickname", null], ["var", "name", null], "anonymous"].unwrap();
+/// assert_eq!(r.evaluate(&json!({"nickname": null, "name": "tom"})).unwrap(), json!("tom"));
+/// assert_eq!(r.evaluate(&json!({})).unwrap(), json!("anonymous"));
+/// ```
+pub fn coalesce(args: &[Arg], ctx: &EvalCtx) -> Result<Arg> {
+    for i in 0..args.len() {
+        let val = eval_arg(args, i, ctx)?;
+        if val != Arg::Null {
+            return Ok(val);
+        }
+    }
+    Ok(Arg::Null)
 }
 
-pub fn div(args: Vec<Arg>) -> Arg {
-    let mut it = args.into_iter();
-    it.next().map(|first| it.fold(first, Div::div)).unwrap_or(Arg::Null)
+pub fn add(args: &[Arg]) -> Result<Arg> {
+    let mut it = args.iter().cloned();
+    Ok(it.next().map(|first| it.fold(first, Add::add)).unwrap_or(Arg::Null))
+}
+
+pub fn sub(args: &[Arg]) -> Result<Arg> {
+    let mut it = args.iter().cloned();
+    Ok(it.next().map(|first| it.fold(first, Sub::sub)).unwrap_or(Arg::Null))
+}
+
+pub fn neg(args: &[Arg]) -> Result<Arg> {
+    Ok(-args.first().unwrap_or(&Arg::Null))
+}
+
+pub fn mul(args: &[Arg]) -> Result<Arg> {
+    let mut it = args.iter().cloned();
+    Ok(it.next().map(|first| it.fold(first, Mul::mul)).unwrap_or(Arg::Null))
+}
+
+pub fn div(args: &[Arg]) -> Result<Arg> {
+    let mut it = args.iter().cloned();
+    Ok(it.next().map(|first| it.fold(first, Div::div)).unwrap_or(Arg::Null))
 }
 
 /// The remainder operator %.
 /// Aliases: %, rem, mod
-pub fn rem(args: Vec<Arg>) -> Arg {
-    let mut it = args.into_iter();
-    it.next().map(|first| it.fold(first, Rem::rem)).unwrap_or(Arg::Null)
+pub fn rem(args: &[Arg]) -> Result<Arg> {
+    let mut it = args.iter().cloned();
+    Ok(it.next().map(|first| it.fold(first, Rem::rem)).unwrap_or(Arg::Null))
 }
 
-/// Computes the absolute value of arg[0].
-pub fn abs(args: Vec<Arg>) -> Arg {
-    let int: i64 = args.get(0).unwrap_or(&Arg::Null).into();
-    Arg::Int(int.abs())
+/// Computes the absolute value of arg[0], null if it overflows.
+pub fn abs(args: &[Arg]) -> Result<Arg> {
+    let int: i64 = args.first().unwrap_or(&Arg::Null).into();
+    Ok(int.checked_abs().map_or(Arg::Null, Arg::Int))
 }
 
-/// Return true if args[0] in args[1..].
+/// Return true if args[0] in args[1..], compared as `eq` does.
 /// e.g. rule json string: ["in", 1, 1, 2, 3]
 /// ```
 /// use ::rule::{rule, json};
 /// assert!(rule!["in", 1, 1, 2, 3].unwrap().matches(&json!({})).unwrap());
+/// assert!(rule!["in", 2.0, 1, 2, 3].unwrap().matches(&json!({})).unwrap());
 /// ```
-pub fn r#in(args: Vec<Arg>) -> Arg {
-    Arg::Bool(args[1..].contains(&args[0]))
+pub fn r#in(args: &[Arg]) -> Result<Arg> {
+    let first = arg(args, 0)?;
+    Ok(Arg::Bool(args[1..].iter().any(|a| first.value_eq(a))))
 }
 
 /// Return true if args[0] starts with args[1]
@@ -218,13 +959,12 @@ pub fn r#in(args: Vec<Arg>) -> Arg {
 /// assert!(rule!["startswith", "hello", "he"].unwrap().matches(&json!({})).unwrap());
 /// assert!(rule!["startswith", "arr", "foo", "bar"].unwrap().matches(&json!({"arr": ["foo", "bar", "baz"]})).unwrap());
 /// ```
-pub fn startswith(args: Vec<Arg>) -> Arg {
-    let ret = match &args[0] {
-        Arg::String(s) => Arg::Bool((&s).starts_with(&args[1].to_string())),
-        Arg::Array(a) => Arg::Bool(a.starts_with(&args[1..])),
-        _ => Arg::Bool(false),
-    };
-    ret
+pub fn startswith(args: &[Arg]) -> Result<Arg> {
+    match arg(args, 0)? {
+        Arg::String(s) => Ok(Arg::Bool(s.starts_with(&arg(args, 1)?.to_string()))),
+        Arg::Array(a) => Ok(Arg::Bool(a.starts_with(&args[1..]))),
+        _ => Ok(Arg::Bool(false)),
+    }
 }
 
 /// Return true if args[0] ends with args[1]
@@ -234,13 +974,215 @@ pub fn startswith(args: Vec<Arg>) -> Arg {
 /// assert!(rule!["endswith", "hello", "lo"].unwrap().matches(&json!({})).unwrap());
 /// assert!(rule!["endswith", "arr", "bar", "baz"].unwrap().matches(&json!({"arr": ["foo", "bar", "baz"]})).unwrap());
 /// ```
-pub fn endswith(args: Vec<Arg>) -> Arg {
-    let ret = match &args[0] {
-        Arg::String(s) => Arg::Bool((&s).ends_with(&args[1].to_string())),
-        Arg::Array(a) => Arg::Bool(a.ends_with(&args[1..])),
-        _ => Arg::Bool(false),
+pub fn endswith(args: &[Arg]) -> Result<Arg> {
+    match arg(args, 0)? {
+        Arg::String(s) => Ok(Arg::Bool(s.ends_with(&arg(args, 1)?.to_string()))),
+        Arg::Array(a) => Ok(Arg::Bool(a.ends_with(&args[1..]))),
+        _ => Ok(Arg::Bool(false)),
+    }
+}
+
+/// The name bound to each element of the collection in the lambda of a collection operator.
+pub const ELEMENT: &str = "$";
+/// The name bound to the accumulated value in the lambda of `reduce`.
+pub const ACCUMULATOR: &str = "$acc";
+
+/// Evaluates the collection args[0] of a lambda operator, null is an empty one.
+fn collection(args: &[Arg], ctx: &EvalCtx) -> Result<Vec<Arg>> {
+    match eval_arg(args, 0, ctx)? {
+        Arg::Array(items) => Ok(items),
+        Arg::Null => Ok(vec![]),
+        _ => Err(Error::type_error()),
+    }
+}
+
+/// Evaluates the lambda args[1] with `$` bound to `item`.
+fn apply(args: &[Arg], ctx: &EvalCtx, item: &Arg) -> Result<Arg> {
+    let lambda = arg(args, 1)?;
+    ctx.bind(ELEMENT, item, |ctx| ctx.eval(lambda))
+}
+
+/// Return true if the lambda args[1] is true for any element of the array args[0],
+/// it's evaluated with `$` bound to each element in turn, and stops on the first true one.
+///
+/// ```
+/// use ::rule::{rule, json};
+/// let context = json!({"items": [{"sku": "A1", "price": 30}, {"sku": "B7", "price": 120}]});
+/// assert!(rule!["some", "items", [">", ["var", "$.price"], 100]].unwrap().matches(&context).unwrap());
+/// assert!(rule!["some", ["split", "a,b", ","], ["=", ["var", "$"], "b"]].unwrap().matches(&json!({})).unwrap());
+/// assert_eq!(rule!["some", "items", ["=", "$.sku", "C3"]].unwrap().matches(&context).unwrap(), false);
+/// ```
+pub fn some(args: &[Arg], ctx: &EvalCtx) -> Result<Arg> {
+    for item in &collection(args, ctx)? {
+        if apply(args, ctx, item)?.into() {
+            return Ok(Arg::Bool(true));
+        }
+    }
+    Ok(Arg::Bool(false))
+}
+
+/// Return true if the lambda args[1] is true for every element of the array args[0], see `some`.
+///
+/// ```
+/// use ::rule::{rule, json};
+/// let context = json!({"items": [{"sku": "A1", "price": 30}, {"sku": "B7", "price": 120}]});
+/// assert!(rule!["every", "items", [">", "$.price", 10]].unwrap().matches(&context).unwrap());
+/// assert!(rule!["every", ["var", "nothing", null], false].unwrap().matches(&context).unwrap());
+/// ```
+pub fn every(args: &[Arg], ctx: &EvalCtx) -> Result<Arg> {
+    for item in &collection(args, ctx)? {
+        if !Into::<bool>::into(apply(args, ctx, item)?) {
+            return Ok(Arg::Bool(false));
+        }
+    }
+    Ok(Arg::Bool(true))
+}
+
+/// Return true if the lambda args[1] is true for no element of the array args[0], see `some`.
+///
+/// ```
+/// use ::rule::{rule, json};
+/// assert!(rule!["none", "tags", ["=", "$", "blocked"]].unwrap().matches(&json!({"tags": ["vip", "beta"]})).unwrap());
+/// ```
+pub fn none(args: &[Arg], ctx: &EvalCtx) -> Result<Arg> {
+    Ok(Arg::Bool(!bool::from(some(args, ctx)?)))
+}
+
+/// Return the elements of the array args[0] the lambda args[1] is true for, see `some`.
+///
+/// ```
+/// use ::rule::{rule, json};
+/// let context = json!({"items": [{"sku": "A1", "price": 30}, {"sku": "B7", "price": 120}]});
+/// assert!(rule!["=", ["map", ["filter", "items", [">", "$.price", 100]], ["var", "$.sku"]], ["lit", ["B7"]]].unwrap().matches(&context).unwrap());
+/// ```
+pub fn filter(args: &[Arg], ctx: &EvalCtx) -> Result<Arg> {
+    let mut items = vec![];
+    for item in collection(args, ctx)? {
+        if apply(args, ctx, &item)?.into() {
+            items.push(item);
+        }
+    }
+    Ok(Arg::Array(items))
+}
+
+/// Return the values of the lambda args[1] for the elements of the array args[0], see `some`.
+///
+/// ```
+/// use ::rule::{rule, json};
+/// let context = json!({"items": [{"sku": "A1", "qty": 2}, {"sku": "B7", "qty": 1}]});
+/// assert!(rule!["=", ["map", "items", ["*", "$.qty", 10]], ["lit", [20, 10]]].unwrap().matches(&context).unwrap());
+/// ```
+pub fn map(args: &[Arg], ctx: &EvalCtx) -> Result<Arg> {
+    let items = collection(args, ctx)?;
+    Ok(Arg::Array(items.iter().map(|item| apply(args, ctx, item)).collect::<Result<_>>()?))
+}
+
+/// Return the number of elements of the array args[0],
+/// or of those the lambda args[1] is true for if it's given, see `some`.
+///
+/// ```
+/// use ::rule::{rule, json};
+/// let context = json!({"items": [{"sku": "A1", "price": 30}, {"sku": "B7", "price": 120}]});
+/// assert!(rule!["=", ["count", "items"], 2].unwrap().matches(&context).unwrap());
+/// assert!(rule!["=", ["count", "items", ["<", "$.price", 100]], 1].unwrap().matches(&context).unwrap());
+/// ```
+pub fn count(args: &[Arg], ctx: &EvalCtx) -> Result<Arg> {
+    let items = collection(args, ctx)?;
+    if args.len() < 2 {
+        return Ok(Arg::Int(items.len() as i64));
+    }
+    let mut n = 0;
+    for item in &items {
+        if apply(args, ctx, item)?.into() {
+            n += 1;
+        }
+    }
+    Ok(Arg::Int(n))
+}
+
+/// Folds the array args[0] into one value, starting from args[2], with the lambda args[1]
+/// evaluated with `$` bound to each element and `$acc` to the value so far.
+///
+/// ```
+/// use ::rule::{rule, json};
+/// use ::rule::arg::Arg;
+/// let context = json!({"items": [{"price": 30, "qty": 2}, {"price": 120, "qty": 1}]});
+/// let total = rule!["reduce", "items", ["+", ["var", "$acc"], ["*", "$.price", ["var", "$.qty"]]], 0].unwrap();
+/// assert_eq!(total.expr().matches_json(&context).unwrap(), Arg::Int(180));
+/// ```
+pub fn reduce(args: &[Arg], ctx: &EvalCtx) -> Result<Arg> {
+    let items = collection(args, ctx)?;
+    let lambda = arg(args, 1)?;
+    let mut acc = ctx.eval(arg(args, 2)?)?;
+    for item in &items {
+        acc = ctx.bind(ACCUMULATOR, &acc, |ctx| ctx.bind(ELEMENT, item, |ctx| ctx.eval(lambda)))?;
+    }
+    Ok(acc)
+}
+
+/// Return the keys of an object args[0], in order.
+///
+/// ```
+/// use ::rule::{rule, json};
+/// assert!(rule!["=", ["keys", "user"], ["split", "age,name", ","]].unwrap().matches(&json!({"user": {"name": "tom", "age": 1}})).unwrap());
+/// ```
+pub fn keys(args: &[Arg]) -> Result<Arg> {
+    let obj = arg(args, 0)?.as_object().ok_or_else(Error::type_error)?;
+    Ok(Arg::Array(obj.keys().map(|k| Arg::String(k.clone())).collect()))
+}
+
+/// Return the values of an object args[0], in the order of keys.
+///
+/// ```
+/// use ::rule::{rule, json};
+/// assert!(rule!["startswith", ["values", "user"], 1, "tom"].unwrap().matches(&json!({"user": {"name": "tom", "age": 1}})).unwrap());
+/// ```
+pub fn values(args: &[Arg]) -> Result<Arg> {
+    let obj = arg(args, 0)?.as_object().ok_or_else(Error::type_error)?;
+    Ok(Arg::Array(obj.values().cloned().collect()))
+}
+
+/// With one arg, return true if the context variable args[0] exists.
+/// With two args, return true if the object args[0] has the key args[1].
+///
+/// ```
+/// use ::rule::{rule, json};
+/// assert!(rule!["has", "user"].unwrap().matches(&json!({"user": {"name": "tom"}})).unwrap());
+/// assert!(rule!["has", "user", "name"].unwrap().matches(&json!({"user": {"name": "tom"}})).unwrap());
+/// assert!(rule!["has", "user.name"].unwrap().matches(&json!({"user": {"name": "tom"}})).unwrap());
+/// assert_eq!(rule!["has", "user", "age"].unwrap().matches(&json!({"user": {"name": "tom"}})).unwrap(), false);
+/// ```
+pub fn has(args: &[Arg], ctx: &EvalCtx) -> Result<Arg> {
+    if args.len() == 1 {
+        let name = ctx.eval(arg(args, 0)?)?;
+        let name = name.as_str().ok_or_else(|| Error::ExprVarArgNotStringError { at: Location::default() })?;
+        return Ok(Arg::Bool(ctx.lookup(name).is_some()));
+    }
+    let obj = eval_arg(args, 0, ctx)?;
+    let key = ctx.eval(arg(args, 1)?)?;
+    match obj {
+        Arg::Object(obj) => Ok(Arg::Bool(obj.contains_key(&String::from(&key)))),
+        _ => Ok(Arg::Bool(false)),
+    }
+}
+
+/// Return the value of the key args[1] in the object args[0], or the item at index args[1] in the array args[0].
+/// Return args[2] if given, or null, if there's no such key.
+///
+/// ```
+/// use ::rule::{rule, json};
+/// assert!(rule!["=", ["get", "user", "name"], "tom"].unwrap().matches(&json!({"user": {"name": "tom"}})).unwrap());
+/// assert!(rule!["=", ["get", "user", "age", 0], 0].unwrap().matches(&json!({"user": {"name": "tom"}})).unwrap());
+/// assert!(rule!["=", ["get", ["split", "a,b", ","], 1], "b"].unwrap().matches(&json!({})).unwrap());
+/// ```
+pub fn get(args: &[Arg]) -> Result<Arg> {
+    let key = arg(args, 1)?;
+    let val = match arg(args, 0)? {
+        Arg::Object(obj) => obj.get(&String::from(key)),
+        Arg::Array(arr) => usize::try_from(Into::<i64>::into(key)).ok().and_then(|i| arr.get(i)),
+        _ => return Err(Error::type_error()),
     };
-    ret
+    Ok(val.or_else(|| args.get(2)).cloned().unwrap_or(Arg::Null))
 }
 
 /// Convert upper case letters to lower case.
@@ -249,8 +1191,8 @@ pub fn endswith(args: Vec<Arg>) -> Arg {
 /// use ::rule::{rule, json};
 /// assert!(rule!["=", ["lower", "Hi"], "hi"].unwrap().matches(&json!({})).unwrap());
 /// ```
-pub fn lower(args: Vec<Arg>) -> Arg {
-    Arg::String(String::from(&args[0]).to_lowercase())
+pub fn lower(args: &[Arg]) -> Result<Arg> {
+    Ok(Arg::String(String::from(arg(args, 0)?).to_lowercase()))
 }
 
 /// Convert lower case letters to upper case.
@@ -259,8 +1201,8 @@ pub fn lower(args: Vec<Arg>) -> Arg {
 /// use ::rule::{rule, json};
 /// assert!(rule!["=", ["upper", "Hi"], "HI"].unwrap().matches(&json!({})).unwrap());
 /// ```
-pub fn upper(args: Vec<Arg>) -> Arg {
-    Arg::String(String::from(&args[0]).to_uppercase())
+pub fn upper(args: &[Arg]) -> Result<Arg> {
+    Ok(Arg::String(String::from(arg(args, 0)?).to_uppercase()))
 }
 
 /// Split strings using a seperator.
@@ -269,10 +1211,10 @@ pub fn upper(args: Vec<Arg>) -> Arg {
 /// use ::rule::{rule, json};
 /// assert!(rule!["startswith", ["split", "apple,pear", ","], "apple"].unwrap().matches(&json!({})).unwrap());
 /// ```
-pub fn split(args: Vec<Arg>) -> Arg {
-    let s = &String::from(&args[0]);
-    let sep = &String::from(&args[1]);
-    Arg::Array(s.split(sep).map(|x| Arg::String(x.to_owned())).collect())
+pub fn split(args: &[Arg]) -> Result<Arg> {
+    let s = &String::from(arg(args, 0)?);
+    let sep = &String::from(arg(args, 1)?);
+    Ok(Arg::Array(s.split(sep).map(|x| Arg::String(x.to_owned())).collect()))
 }
 
 /// Concatenate strings with a seperator.
@@ -281,10 +1223,38 @@ pub fn split(args: Vec<Arg>) -> Arg {
 /// use ::rule::{rule, json};
 /// assert!(rule!["=", ["join", " ", "hello", "world"], "hello world"].unwrap().matches(&json!({})).unwrap());
 /// ```
-pub fn join(args: Vec<Arg>) -> Arg {
-    let mut it = args.into_iter();
-    let sep = &String::from(&it.nth(0).unwrap_or(Arg::String("".to_owned())));
-    Arg::String(it.map(|x| String::from(&x)).collect::<Vec<String>>().join(sep))
+pub fn join(args: &[Arg]) -> Result<Arg> {
+    let mut it = args.iter();
+    let sep = &it.next().map(String::from).unwrap_or_default();
+    Ok(Arg::String(it.map(String::from).collect::<Vec<String>>().join(sep)))
+}
+
+/// A literal pattern of `match` or `regex` is compiled once when the expression is built, and rejected there if it's invalid,
+/// the patterns from context variables are compiled on first use, this many are kept cached for each of regex and glob.
+const PATTERN_CACHE_SIZE: usize = 256;
+
+lazy_static! {
+    static ref GLOB_CACHE: Mutex<LruCache<String, Arc<glob::Pattern>>> = Mutex::new(LruCache::new(NonZeroUsize::new(PATTERN_CACHE_SIZE).unwrap()));
+    static ref REGEX_CACHE: Mutex<LruCache<String, regex::Regex>> = Mutex::new(LruCache::new(NonZeroUsize::new(PATTERN_CACHE_SIZE).unwrap()));
+}
+
+/// Returns the compiled `patt` from `cache`, compiles and caches it on a miss.
+/// The lock isn't held while compiling, two threads may compile the same pattern at once.
+fn cached<T: Clone>(cache: &Mutex<LruCache<String, T>>, patt: &str, compile: fn(&str) -> Result<T>) -> Result<T> {
+    if let Some(val) = cache.lock().unwrap_or_else(PoisonError::into_inner).get(patt) {
+        return Ok(val.clone());
+    }
+    let val = compile(patt)?;
+    cache.lock().unwrap_or_else(PoisonError::into_inner).put(patt.to_owned(), val.clone());
+    Ok(val)
+}
+
+fn new_glob(patt: &str) -> Result<Arc<glob::Pattern>> {
+    glob::Pattern::new(patt).map(Arc::new).map_err(|source| Error::InvalidGlob { source, at: Location::default() })
+}
+
+fn new_regex(patt: &str) -> Result<regex::Regex> {
+    regex::Regex::new(patt).map_err(|source| Error::InvalidRegex { source, at: Location::default() })
 }
 
 /// Match string using an Unix shell style pattern.
@@ -292,14 +1262,20 @@ pub fn join(args: Vec<Arg>) -> Arg {
 /// ```
 /// use ::rule::{rule, json};
 /// assert!(rule!["match", "hello", "he*"].unwrap().matches(&json!({})).unwrap());
+/// assert!(rule!["match", "hello", ["var", "p"]].unwrap().matches(&json!({"p": "h?llo"})).unwrap());
+/// assert!(rule!["match", "hello", "[he"].is_err());
 /// ```
-pub fn r#match(args: Vec<Arg>) -> Arg {
-    match glob::Pattern::new(&String::from(&args[1])) {
-        Ok(patt) => {
-            Arg::Bool(patt.matches(&String::from(&args[0])))
-        },
-        Err(_) => Arg::Bool(false),
-    }
+pub fn r#match(args: &[Arg]) -> Result<Arg> {
+    let patt = cached(&GLOB_CACHE, &String::from(arg(args, 1)?), new_glob)?;
+    Ok(Arg::Bool(patt.matches(&String::from(arg(args, 0)?))))
+}
+
+fn compile_glob(args: &[Arg]) -> Result<Option<Func>> {
+    let patt = match args.get(1) {
+        Some(Arg::String(patt)) => new_glob(patt)?,
+        _ => return Ok(None),
+    };
+    Ok(Some(Arc::new(move |args, _| Ok(Arg::Bool(patt.matches(&String::from(arg(args, 0)?)))))))
 }
 
 /// Match strings using regular expressions.
@@ -307,14 +1283,20 @@ pub fn r#match(args: Vec<Arg>) -> Arg {
 /// ```
 /// use ::rule::{rule, json};
 /// assert!(rule!["regex", "hello", "^he[l-o]*$"].unwrap().matches(&json!({})).unwrap());
+/// assert!(rule!["regex", "hello", ["var", "re"]].unwrap().matches(&json!({"re": "l+"})).unwrap());
+/// assert!(rule!["regex", "hello", "(he"].is_err());
 /// ```
-pub fn regex(args: Vec<Arg>) -> Arg {
-    match regex::Regex::new(&String::from(&args[1])) {
-        Ok(re) => {
-            Arg::Bool(re.is_match(&String::from(&args[0])))
-        },
-        Err(_) => Arg::Bool(false),
-    }
+pub fn regex(args: &[Arg]) -> Result<Arg> {
+    let re = cached(&REGEX_CACHE, &String::from(arg(args, 1)?), new_regex)?;
+    Ok(Arg::Bool(re.is_match(&String::from(arg(args, 0)?))))
+}
+
+fn compile_regex(args: &[Arg]) -> Result<Option<Func>> {
+    let re = match args.get(1) {
+        Some(Arg::String(patt)) => new_regex(patt)?,
+        _ => return Ok(None),
+    };
+    Ok(Some(Arc::new(move |args, _| Ok(Arg::Bool(re.is_match(&String::from(arg(args, 0)?)))))))
 }
 
 /// Convert a string into a number.
@@ -325,21 +1307,22 @@ pub fn regex(args: Vec<Arg>) -> Arg {
 /// assert!(rule!["=", ["num", "1.23"], 1.23].unwrap().matches(&json!({})).unwrap());
 /// assert_eq!(rule!["=", "100", 100].unwrap().matches(&json!({})).unwrap(), false);
 /// ```
-pub fn num(args: Vec<Arg>) -> Arg {
-    let a = args[0].clone();
+pub fn num(args: &[Arg]) -> Result<Arg> {
+    let a = arg(args, 0)?.clone();
     match a {
-        Arg::Int(_) => a,
-        Arg::Float(_) => a,
+        Arg::Int(_) => Ok(a),
+        Arg::Float(_) => Ok(a),
         Arg::String(v) => {
             match v.parse::<i64>() {
-                Ok(i) => Arg::Int(i),
+                Ok(i) => Ok(Arg::Int(i)),
                 Err(_) => match v.parse::<f64>() {
-                    Ok(f) => Arg::Float(f),
-                    Err(_) => Arg::Int(0i64),
+                    Ok(f) => Ok(Arg::Float(f)),
+                    Err(_) => Err(Error::type_error()),
                 }
             }
         },
-        _ => Arg::Int(Into::<i64>::into(a)),
+        Arg::Null | Arg::Bool(_) => Ok(Arg::Int(Into::<i64>::into(a))),
+        _ => Err(Error::type_error()),
     }
 }
 
@@ -349,8 +1332,249 @@ pub fn num(args: Vec<Arg>) -> Arg {
 /// use ::rule::{rule, json};
 /// assert!(rule!["=", ["string", 100], "100"].unwrap().matches(&json!({})).unwrap());
 /// ```
-pub fn string(args: Vec<Arg>) -> Arg {
-    Arg::String(String::from(&args[0]))
+pub fn string(args: &[Arg]) -> Result<Arg> {
+    Ok(Arg::String(String::from(arg(args, 0)?)))
+}
+
+/// Converts a datetime arg: a RFC 3339 string, a date like `2024-05-06` at midnight UTC,
+/// or a Unix timestamp in seconds, returns `None` for null.
+fn to_datetime(arg: &Arg) -> Result<Option<DateTime>> {
+    let invalid = || Error::InvalidDateTime { value: arg.to_string(), at: Location::default() };
+    let utc = match arg {
+        Arg::Null => return Ok(None),
+        Arg::DateTime(v) => return Ok(Some(*v)),
+        Arg::String(v) => match DateTime::parse_from_rfc3339(v) {
+            Ok(v) => return Ok(Some(v)),
+            Err(_) => NaiveDate::parse_from_str(v, "%Y-%m-%d").ok().and_then(|date| date.and_hms_opt(0, 0, 0)).map(|v| v.and_utc()),
+        },
+        Arg::Int(v) => chrono::DateTime::from_timestamp(*v, 0),
+        Arg::Float(v) if v.is_finite() => {
+            let nanos = ((v - v.floor()) * 1e9) as u32;
+            chrono::DateTime::from_timestamp(v.floor() as i64, nanos.min(999_999_999))
+        },
+        Arg::Float(_) => None,
+        _ => return Err(Error::type_error()),
+    };
+    utc.map(|v| Some(v.fixed_offset())).ok_or_else(invalid)
+}
+
+/// Converts a duration arg: a number of seconds, or a string of numbers with units like `1h30m` or `-30d`,
+/// the units are `w`, `d`, `h`, `m`, `s` and `ms`.
+fn to_duration(arg: &Arg) -> Result<TimeDelta> {
+    let duration = match arg {
+        Arg::Int(v) => TimeDelta::try_seconds(*v),
+        Arg::Float(v) if v.is_finite() => TimeDelta::try_milliseconds((v * 1000.0).round() as i64),
+        Arg::Float(_) => None,
+        Arg::String(v) => parse_duration(v),
+        _ => return Err(Error::type_error()),
+    };
+    duration.ok_or_else(|| Error::InvalidDuration { value: arg.to_string(), at: Location::default() })
+}
+
+fn parse_duration(s: &str) -> Option<TimeDelta> {
+    let (negative, mut rest) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    if rest.is_empty() {
+        return None;
+    }
+    let mut total = TimeDelta::zero();
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let n: i64 = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+        let unit = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        let duration = match &rest[..unit] {
+            "w" => TimeDelta::try_weeks(n),
+            "d" => TimeDelta::try_days(n),
+            "h" => TimeDelta::try_hours(n),
+            "m" => TimeDelta::try_minutes(n),
+            "s" => TimeDelta::try_seconds(n),
+            "ms" => TimeDelta::try_milliseconds(n),
+            _ => None,
+        }?;
+        total = total.checked_add(&duration)?;
+        rest = &rest[unit..];
+    }
+    Some(if negative { -total } else { total })
+}
+
+/// A time zone by its IANA name like `Europe/Berlin`, or a fixed offset like `+02:00`.
+#[derive(Clone, Copy)]
+enum Zone {
+    Named(Tz),
+    Fixed(FixedOffset),
+}
+
+impl Zone {
+    fn parse(name: &str) -> Result<Zone> {
+        name.parse().map(Zone::Named)
+            .or_else(|_| name.parse().map(Zone::Fixed))
+            .map_err(|_| Error::InvalidTimeZone { value: name.to_owned(), at: Location::default() })
+    }
+
+    /// The same instant as `v`, in the offset of this zone at that instant.
+    fn convert(self, v: &DateTime) -> DateTime {
+        match self {
+            Zone::Named(tz) => v.with_timezone(&tz).fixed_offset(),
+            Zone::Fixed(offset) => v.with_timezone(&offset),
+        }
+    }
+}
+
+/// Converts args[0] to a datetime, from a RFC 3339 string, a date like `2024-05-06` at midnight UTC,
+/// or a Unix timestamp in seconds. Null stays null, like in the other datetime operators,
+/// which convert their datetime args the same way.
+///
+/// ```
+/// use ::rule::{rule, json};
+/// assert!(rule!["=", ["datetime", "ts"], ["datetime", "2024-05-06T12:00:00+02:00"]].unwrap().matches(&json!({"ts": 1714989600})).unwrap());
+/// assert!(rule!["<", ["datetime", "2024-05-06"], ["datetime", "ts"]].unwrap().matches(&json!({"ts": "2024-05-06T00:00:01Z"})).unwrap());
+/// assert!(rule!["datetime", "ts"].unwrap().matches(&json!({"ts": "tomorrow"})).is_err());
+/// ```
+pub fn datetime(args: &[Arg]) -> Result<Arg> {
+    Ok(to_datetime(arg(args, 0)?)?.map_or(Arg::Null, Arg::DateTime))
+}
+
+/// Returns the current time in UTC, or `Options::now` if it's set.
+/// It's lazy so that it's evaluated every time, `Rule::optimize` never folds it into a constant.
+///
+/// ```
+/// use ::rule::{Rule, json};
+/// use ::rule::ctx::Options;
+///
+/// let options = Options { now: Some("2024-05-06T10:00:00Z".parse().unwrap()), ..Options::default() };
+/// let r = Rule::new(json!(["<", ["datetime", "created_at"], ["dateadd", ["now"], "-30d"]])).unwrap().with_options(options);
+/// assert!(r.matches(&json!({"created_at": "2024-03-01T00:00:00Z"})).unwrap());
+/// assert!(!r.matches(&json!({"created_at": "2024-05-01T00:00:00Z"})).unwrap());
+/// ```
+pub fn now(_args: &[Arg], ctx: &EvalCtx) -> Result<Arg> {
+    Ok(Arg::DateTime(ctx.options().now.unwrap_or_else(|| Utc::now().fixed_offset())))
+}
+
+fn shift(v: &Arg, duration: TimeDelta) -> Result<Arg> {
+    Ok(to_datetime(v)?.and_then(|v| v.checked_add_signed(duration)).map_or(Arg::Null, Arg::DateTime))
+}
+
+/// Adds the duration args[1] to the datetime args[0], null if it overflows.
+/// The duration is a number of seconds, or a string like `1h30m` or `-30d`,
+/// of the units `w`, `d`, `h`, `m`, `s` and `ms`. A literal duration is parsed when the expression is built.
+///
+/// ```
+/// use ::rule::{rule, json};
+/// assert!(rule!["=", ["dateadd", "ts", "1d12h"], ["datetime", "2024-05-07T12:00:00Z"]].unwrap().matches(&json!({"ts": "2024-05-06"})).unwrap());
+/// assert!(rule!["=", ["dateadd", "ts", -90], ["datetime", "2024-05-05T23:58:30Z"]].unwrap().matches(&json!({"ts": "2024-05-06"})).unwrap());
+/// assert!(rule!["dateadd", "ts", "1y"].is_err());
+/// ```
+pub fn dateadd(args: &[Arg]) -> Result<Arg> {
+    shift(arg(args, 0)?, to_duration(arg(args, 1)?)?)
+}
+
+fn compile_duration(args: &[Arg]) -> Result<Option<Func>> {
+    let duration = match args.get(1) {
+        Some(arg @ (Arg::String(_) | Arg::Int(_) | Arg::Float(_))) => to_duration(arg)?,
+        _ => return Ok(None),
+    };
+    Ok(Some(Arc::new(move |args, _| shift(arg(args, 0)?, duration))))
+}
+
+/// Returns the seconds from the datetime args[1] to args[0], null if either is null.
+///
+/// ```
+/// use ::rule::{rule, json};
+/// assert!(rule!["=", ["datediff", "end", ["var", "start"]], 5400].unwrap().matches(&json!({"start": "2024-05-06T10:00:00Z", "end": "2024-05-06T11:30:00Z"})).unwrap());
+/// ```
+pub fn datediff(args: &[Arg]) -> Result<Arg> {
+    let (a, b) = match (to_datetime(arg(args, 0)?)?, to_datetime(arg(args, 1)?)?) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return Ok(Arg::Null),
+    };
+    let duration = a.signed_duration_since(b);
+    Ok(match duration.subsec_nanos() {
+        0 => Arg::Int(duration.num_seconds()),
+        nanos => Arg::Float(duration.num_seconds() as f64 + nanos as f64 / 1e9),
+    })
+}
+
+fn convert(v: &Arg, zone: Zone) -> Result<Arg> {
+    Ok(to_datetime(v)?.map_or(Arg::Null, |v| Arg::DateTime(zone.convert(&v))))
+}
+
+/// Converts the datetime args[0] to the time zone args[1], an IANA name like `Europe/Berlin`,
+/// or a fixed offset like `+02:00`. It's the same instant, but its components are of the zone.
+/// A literal time zone is parsed when the expression is built.
+///
+/// ```
+/// use ::rule::{rule, json};
+/// assert!(rule!["=", ["hour", ["timezone", "ts", "Europe/Berlin"]], 12].unwrap().matches(&json!({"ts": "2024-05-06T10:00:00Z"})).unwrap());
+/// assert!(rule!["=", ["hour", ["timezone", "ts", "Europe/Berlin"]], 11].unwrap().matches(&json!({"ts": "2024-01-06T10:00:00Z"})).unwrap());
+/// assert!(rule!["timezone", "ts", "Mars/Olympus"].is_err());
+/// ```
+pub fn timezone(args: &[Arg]) -> Result<Arg> {
+    let zone = Zone::parse(&String::from(arg(args, 1)?))?;
+    convert(arg(args, 0)?, zone)
+}
+
+fn compile_timezone(args: &[Arg]) -> Result<Option<Func>> {
+    let zone = match args.get(1) {
+        Some(Arg::String(name)) => Zone::parse(name)?,
+        _ => return Ok(None),
+    };
+    Ok(Some(Arc::new(move |args, _| convert(arg(args, 0)?, zone))))
+}
+
+/// Returns a component of the datetime args[0], in its own offset, null if it's null.
+fn component(args: &[Arg], f: fn(&DateTime) -> i64) -> Result<Arg> {
+    Ok(to_datetime(arg(args, 0)?)?.map_or(Arg::Null, |v| Arg::Int(f(&v))))
+}
+
+/// Returns the year of the datetime args[0].
+/// The components are of the offset the datetime is in, which is UTC for a timestamp,
+/// convert it with `timezone` to get them in another one.
+///
+/// ```
+/// use ::rule::{rule, json};
+/// let context = json!({"ts": "2024-05-06T23:30:15+02:00"});
+/// assert!(rule!["=", ["year", "ts"], 2024].unwrap().matches(&context).unwrap());
+/// assert!(rule!["=", ["month", "ts"], 5].unwrap().matches(&context).unwrap());
+/// assert!(rule!["=", ["day", "ts"], 6].unwrap().matches(&context).unwrap());
+/// assert!(rule!["=", ["weekday", "ts"], 1].unwrap().matches(&context).unwrap());
+/// assert!(rule!["=", ["hour", "ts"], 23].unwrap().matches(&context).unwrap());
+/// assert!(rule!["=", ["hour", ["timezone", "ts", "UTC"]], 21].unwrap().matches(&context).unwrap());
+/// ```
+pub fn year(args: &[Arg]) -> Result<Arg> {
+    component(args, |v| v.year().into())
+}
+
+/// Returns the month of the datetime args[0], from 1 to 12.
+pub fn month(args: &[Arg]) -> Result<Arg> {
+    component(args, |v| v.month().into())
+}
+
+/// Returns the day of the month of the datetime args[0], from 1.
+pub fn day(args: &[Arg]) -> Result<Arg> {
+    component(args, |v| v.day().into())
+}
+
+/// Returns the day of the week of the datetime args[0], from 1 for Monday to 7 for Sunday.
+pub fn weekday(args: &[Arg]) -> Result<Arg> {
+    component(args, |v| v.weekday().number_from_monday().into())
+}
+
+/// Returns the hour of the datetime args[0], from 0 to 23.
+pub fn hour(args: &[Arg]) -> Result<Arg> {
+    component(args, |v| v.hour().into())
+}
+
+/// Returns the minute of the datetime args[0], from 0 to 59.
+pub fn minute(args: &[Arg]) -> Result<Arg> {
+    component(args, |v| v.minute().into())
+}
+
+/// Returns the second of the datetime args[0], from 0 to 59.
+pub fn second(args: &[Arg]) -> Result<Arg> {
+    component(args, |v| v.second().into())
 }
 
 // TODO: add more OPs
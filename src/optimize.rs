@@ -0,0 +1,181 @@
+//! Constant folding and simplification of rules, see `Rule::optimize`.
+//!
+//! The pass rewrites the expression bottom-up:
+//! - an eager built-in operator with constant args is evaluated with its own function,
+//!   unless it fails, then the error is left for the evaluation;
+//! - nested `and`s and `or`s are flattened;
+//! - the constants which can't change the result of an `and` or `or` are removed,
+//!   and the args after a constant which decides it are dropped;
+//! - a double negation of an expression returning a bool is removed.
+//!
+//! A literal string first arg may name a context variable, so it's never a constant,
+//! and a constant string which ends up there is kept in a `lit`, like the arrays.
+//! That holds in strict mode too, the options of the optimized rule can be changed afterwards.
+
+use serde_json::Map;
+
+use crate::arg::Arg;
+use crate::ctx::EvalCtx;
+use crate::op::{Kind, OpRegistry};
+use crate::rule::Expr;
+
+/// Returns the optimized `expr`, which evaluates to the same result in any context, with any options.
+pub(crate) fn optimize(expr: &Expr) -> Expr {
+    let optimizer = Optimizer;
+    match optimizer.expr(expr) {
+        Arg::Expr(expr) => expr,
+        val => optimizer.lit(val),
+    }
+}
+
+struct Optimizer;
+
+impl Optimizer {
+    /// Returns true if `arg` at index `i` would be tried as a context variable, unless in strict mode.
+    fn resolvable(&self, i: usize, arg: &Arg) -> bool {
+        i == 0 && matches!(arg, Arg::String(_))
+    }
+
+    /// The value of `arg` at index `i` if it's a constant.
+    fn constant(&self, i: usize, arg: &Arg) -> Option<Arg> {
+        match arg {
+            Arg::String(_) if self.resolvable(i, arg) => None,
+            Arg::Expr(expr) if expr.op().builtin == Some("lit") => expr.args().first().cloned(),
+            Arg::Expr(_) => None,
+            Arg::Object(map) => Some(Arg::Object(map.iter()
+                .map(|(k, v)| Some((k.clone(), self.constant(1, v)?)))
+                .collect::<Option<_>>()?)),
+            arg => Some(arg.clone()),
+        }
+    }
+
+    /// `["lit", val]`
+    fn lit(&self, val: Arg) -> Expr {
+        let op = OpRegistry::builtin().get("lit").expect("`lit` is a built-in operator");
+        Expr::from_parts(op.clone(), vec![val], None)
+    }
+
+    /// Puts a constant at index `i` of the args, in a `lit` if it would be tried as a context variable,
+    /// or if it contains an array, which would be parsed back as an expression.
+    fn arg(&self, i: usize, val: Arg) -> Arg {
+        fn has_array(val: &Arg) -> bool {
+            match val {
+                Arg::Array(_) => true,
+                Arg::Object(map) => map.values().any(has_array),
+                _ => false,
+            }
+        }
+        if self.resolvable(i, &val) || has_array(&val) {
+            Arg::Expr(self.lit(val))
+        } else {
+            val
+        }
+    }
+
+    /// Optimizes an arg, the constants are unwrapped from `lit`s where they're not needed.
+    fn optimize_arg(&self, i: usize, arg: &Arg) -> Arg {
+        match arg {
+            Arg::Expr(expr) => match self.expr(expr) {
+                Arg::Expr(expr) => match self.constant(i, &Arg::Expr(expr.clone())) {
+                    Some(val) => self.arg(i, val),
+                    None => Arg::Expr(expr),
+                },
+                val => self.arg(i, val),
+            },
+            Arg::Object(map) => Arg::Object(map.iter().map(|(k, v)| (k.clone(), self.optimize_arg(1, v))).collect()),
+            arg => arg.clone(),
+        }
+    }
+
+    /// Optimizes an expression, returns the constant it folds to, or the new expression.
+    fn expr(&self, expr: &Expr) -> Arg {
+        let op = expr.op();
+        if op.quote || (op.lazy && op.builtin.is_none()) {
+            // the args are data, or their use is unknown
+            return Arg::Expr(expr.clone());
+        }
+        let args: Vec<Arg> = expr.args().iter().enumerate().map(|(i, arg)| self.optimize_arg(i, arg)).collect();
+        let args = match op.builtin {
+            Some("and") => return self.logic(expr, args, false),
+            Some("or") => return self.logic(expr, args, true),
+            Some("not") => match args.as_slice() {
+                [Arg::Expr(inner)] if inner.op().builtin == Some("not") => match inner.args() {
+                    [Arg::Expr(x)] if x.op().signature.ret == Kind::BOOL => return Arg::Expr(x.clone()),
+                    _ => args,
+                },
+                _ => args,
+            },
+            _ => args,
+        };
+        if op.builtin.is_some() && !op.lazy {
+            if let Some(val) = self.fold(expr, &args) {
+                return val;
+            }
+        }
+        self.rebuild(expr, args)
+    }
+
+    /// Evaluates an eager built-in operator if all the args are constants.
+    /// The infinities, NaN and datetimes are not folded, they have no Json literal.
+    fn fold(&self, expr: &Expr, args: &[Arg]) -> Option<Arg> {
+        let args = args.iter().enumerate().map(|(i, arg)| self.constant(i, arg)).collect::<Option<Vec<_>>>()?;
+        let context = Map::new();
+        let ctx = EvalCtx::new(&context);
+        (expr.op().func)(&args, &ctx).ok().filter(|val| match val {
+            Arg::Float(v) => v.is_finite(),
+            Arg::DateTime(_) => false,
+            _ => true,
+        })
+    }
+
+    /// Flattens the nested `and`s (or `or`s), removes the constants which can't change the result,
+    /// and drops the args after a constant which decides it, that is `until` for `or` and its negation for `and`.
+    /// Returns the result if it's known, or the expression of the remaining args.
+    fn logic(&self, expr: &Expr, args: Vec<Arg>, until: bool) -> Arg {
+        let builtin = expr.op().builtin;
+        let mut flat = vec![];
+        for arg in args {
+            match arg {
+                Arg::Expr(inner) if inner.op().builtin == builtin
+                    && (flat.is_empty() || !inner.args().first().is_some_and(|first| self.resolvable(0, first))) => {
+                    flat.extend(inner.args().iter().cloned());
+                },
+                arg => flat.push(arg),
+            }
+        }
+
+        let mut rest = vec![];
+        for (i, arg) in flat.into_iter().enumerate() {
+            match self.constant(i, &arg) {
+                Some(val) if bool::from(&val) == until => {
+                    if rest.is_empty() {
+                        return Arg::Bool(until);
+                    }
+                    rest.push(Arg::Bool(until));
+                    break;
+                },
+                Some(_) => (),
+                None => rest.push(arg),
+            }
+        }
+        match rest.as_slice() {
+            [] => Arg::Bool(!until),
+            // a single expression returning a bool is the result itself
+            [Arg::Expr(x)] if x.op().signature.ret == Kind::BOOL => Arg::Expr(x.clone()),
+            _ => self.rebuild(expr, rest),
+        }
+    }
+
+    /// The expression with the new args, or the original one if they don't fit the operator,
+    /// e.g. a folded constant of a kind the operator doesn't take.
+    fn rebuild(&self, expr: &Expr, args: Vec<Arg>) -> Arg {
+        let op = expr.op();
+        if op.signature.check(&op.name, &args).is_err() {
+            return Arg::Expr(expr.clone());
+        }
+        match op.compile(&args) {
+            Ok(compiled) => Arg::Expr(Expr::from_parts(compiled, args, expr.span())),
+            Err(_) => Arg::Expr(expr.clone()),
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use serde_json::value::{Value as Json};
+use serde_json::Map;
+
+use crate::arg::Arg;
+
+/// The path to a context variable, which may be nested in objects and arrays.
+///
+/// A path is either
+/// - a dotted path, e.g. `user.address.country` or `items.0.sku`,
+///   use `\.` for a literal dot in a key and `\\` for a literal backslash;
+/// - or a [JSON Pointer](https://tools.ietf.org/html/rfc6901) starting with `/`, e.g. `/items/0/sku`.
+///
+/// ```
+/// use ::rule::json;
+/// use ::rule::path::Path;
+///
+/// let context = json!({"items": [{"sku": "A1"}], "a.b": 1});
+/// let context = context.as_object().unwrap();
+/// assert_eq!(Path::parse("items.0.sku").lookup(context), Some(&json!("A1")));
+/// assert_eq!(Path::parse("/items/0/sku").lookup(context), Some(&json!("A1")));
+/// assert_eq!(Path::parse(r"a\.b").lookup(context), Some(&json!(1)));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Path {
+    segments: Vec<String>,
+}
+
+impl Path {
+    /// Parses a dotted path or a JSON Pointer.
+    pub fn parse(s: &str) -> Path {
+        if let Some(pointer) = s.strip_prefix('/') {
+            Path::from_pointer(pointer)
+        } else {
+            Path::from_dotted(s)
+        }
+    }
+
+    fn from_pointer(s: &str) -> Path {
+        Path {
+            segments: s.split('/').map(|seg| seg.replace("~1", "/").replace("~0", "~")).collect(),
+        }
+    }
+
+    fn from_dotted(s: &str) -> Path {
+        let mut segments = vec![];
+        let mut seg = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some(next @ '.') | Some(next @ '\\') => seg.push(next),
+                    Some(next) => {
+                        seg.push(c);
+                        seg.push(next);
+                    },
+                    None => seg.push(c),
+                },
+                '.' => segments.push(std::mem::take(&mut seg)),
+                _ => seg.push(c),
+            }
+        }
+        segments.push(seg);
+        Path { segments }
+    }
+
+    /// Returns the keys and indices of the path.
+    pub fn segments(&self) -> &[String] {
+        &self.segments
+    }
+
+    /// Looks up the value at this path in a Json Dict context, returns `None` if not exists.
+    pub fn lookup<'a>(&self, context: &'a Map<String, Json>) -> Option<&'a Json> {
+        let (first, rest) = self.segments.split_first()?;
+        lookup_json(rest, context.get(first)?)
+    }
+}
+
+/// Looks up the value at the keys and indices `segments` in a Json value, returns `None` if not exists.
+pub(crate) fn lookup_json<'a>(segments: &[String], val: &'a Json) -> Option<&'a Json> {
+    segments.iter().try_fold(val, |val, seg| match val {
+        Json::Object(map) => map.get(seg),
+        Json::Array(arr) => seg.parse::<usize>().ok().and_then(|i| arr.get(i)),
+        _ => None,
+    })
+}
+
+/// Looks up the value at the keys and indices `segments` in an `Arg`, returns `None` if not exists.
+pub(crate) fn lookup_arg<'a>(segments: &[String], val: &'a Arg) -> Option<&'a Arg> {
+    segments.iter().try_fold(val, |val, seg| match val {
+        Arg::Object(map) => map.get(seg),
+        Arg::Array(arr) => seg.parse::<usize>().ok().and_then(|i| arr.get(i)),
+        _ => None,
+    })
+}
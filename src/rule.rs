@@ -1,15 +1,40 @@
-use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::result;
+
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de;
 use serde_json::value::{to_value, Value as Json};
 use serde_json::Map;
 
-use crate::op::Op;
-use crate::error::{Error, Result};
+use crate::op::{self, Op, OpRegistry};
+use crate::error::{Error, ExprPath, Result, Span};
 use crate::arg::Arg;
+use crate::ctx::{EvalCtx, Options};
+use crate::text;
+use crate::trace::{Trace, Tracer};
+use crate::types::{self, Diagnostic, Schema};
+use crate::compile::CompiledRule;
+use crate::optimize;
+use crate::path::Path;
 
-/// The Rule type, contains an `Expr`.
+/// The Rule type, contains an `Expr` and the evaluation `Options`.
+///
+/// A `Rule` serializes to its Json expression, and deserializes from it with the built-in operators,
+/// the `Options` are not included.
+///
+/// ```
+/// use ::rule::{Rule, rule, json};
+///
+/// let r = rule!["&", ["=", "a", 1], ["in", ["lit", "x"], "x", "y"]].unwrap();
+/// assert_eq!(r.to_json(), json!(["&", ["=", "a", 1], ["in", ["lit", "x"], "x", "y"]]));
+/// assert_eq!(Rule::new(r.to_json()).unwrap(), r);
+/// assert_eq!(serde_json::from_str::<Rule>(&serde_json::to_string(&r).unwrap()).unwrap(), r);
+/// ```
 #[derive(Clone, Debug, PartialEq)]
 pub struct Rule {
     expr: Expr,
+    options: Options,
 }
 
 impl Rule {
@@ -17,6 +42,16 @@ impl Rule {
     pub fn new(val: Json) -> Result<Rule> {
         Ok(Rule {
             expr: Expr::new(val)?,
+            options: Options::default(),
+        })
+    }
+
+    /// Constructs a new `Rule` from a serde Json Value object,
+    /// resolves the operators against a custom `OpRegistry`.
+    pub fn with_registry(val: Json, registry: &OpRegistry) -> Result<Rule> {
+        Ok(Rule {
+            expr: Expr::new_in(val, registry)?,
+            options: Options::default(),
         })
     }
 
@@ -26,46 +61,342 @@ impl Rule {
     }
 
     /// Constructs a new `Rule` from a json string.
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Result<Rule> {
         Rule::new(serde_json::from_str(s)?)
     }
 
+    /// Constructs a new `Rule` from an expression in the infix text syntax, see [`text`](../text/index.html).
+    ///
+    /// ```
+    /// use ::rule::{Rule, json};
+    ///
+    /// let r = Rule::parse_expr("age > 18 && country in ('DE', 'FR')").unwrap();
+    /// assert_eq!(r, Rule::new(json!(["&&", [">", ["var", "age"], 18], ["in", ["var", "country"], "DE", "FR"]])).unwrap());
+    /// assert!(r.matches(&json!({"age": 20, "country": "DE"})).unwrap());
+    /// assert_eq!(r.to_text(), "age > 18 && country in ('DE', 'FR')");
+    /// ```
+    pub fn parse_expr(s: &str) -> Result<Rule> {
+        Rule::parse_expr_in(s, OpRegistry::builtin())
+    }
+
+    /// Constructs a new `Rule` from an expression in the infix text syntax,
+    /// resolves the operators against a custom `OpRegistry`.
+    pub fn parse_expr_in(s: &str, registry: &OpRegistry) -> Result<Rule> {
+        Ok(Rule {
+            expr: Expr::parse_in(s, registry)?,
+            options: Options::default(),
+        })
+    }
+
+    /// Sets the evaluation options.
+    ///
+    /// ```
+    /// use ::rule::{rule, json};
+    /// use ::rule::ctx::{Options, MissingVar};
+    ///
+    /// let r = rule!["=", ["var", "x"], null].unwrap();
+    /// assert!(r.matches(&json!({})).is_err());
+    /// let r = r.with_options(Options { missing_var: MissingVar::Null, ..Options::default() });
+    /// assert!(r.matches(&json!({})).unwrap());
+    /// ```
+    pub fn with_options(self, options: Options) -> Rule {
+        Rule {
+            options,
+            ..self
+        }
+    }
+
+    /// Returns the evaluation options.
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Evaluates the rule with a context and returns the evaluation trace,
+    /// which tells the resolved args, the context variables read and the result of every node.
+    /// An error in evaluation is recorded in the trace instead of returned.
+    ///
+    /// ```
+    /// use ::rule::{rule, json, arg::Arg};
+    ///
+    /// let r = rule!["&&", ["=", "a", 1], ["in", "country", "DE", "FR"]].unwrap();
+    /// let trace = r.explain(&json!({"a": 1, "country": "DE"})).unwrap();
+    /// assert_eq!(trace.result, Some(Arg::Bool(true)));
+    /// assert_eq!(trace.to_string(), r#"&&(true, true) => true
+    ///   =(1, 1) => true
+    ///     | a = 1
+    ///   in("DE", "DE", "FR") => true
+    ///     | country = "DE"
+    /// "#);
+    /// ```
+    pub fn explain<T: Serialize>(&self, context: &T) -> Result<Trace> {
+        let context = to_value(context)?;
+        let tracer = Tracer::default();
+        let ctx = EvalCtx::new(context.as_object().ok_or(Error::ContextNotDictError)?)
+            .with_options(&self.options)
+            .with_tracer(&tracer);
+        let _ = self.expr.eval(&ctx);
+        Ok(tracer.finish().expect("the root expression is always traced"))
+    }
+
+    /// Infers the kind of every expression of the rule, given the kinds of the context variables,
+    /// and returns the type conflicts, e.g. adding a string to a number, or a rule which can't return a bool.
+    ///
+    /// ```
+    /// use ::rule::{rule, json};
+    /// use ::rule::op::Kind;
+    /// use ::rule::types::Schema;
+    ///
+    /// let schema = Schema::new().var("name", Kind::STRING).var("age", Kind::NUMBER);
+    /// assert!(rule!["&&", [">", "age", 18], ["startswith", "name", "t"]].unwrap().typecheck(&schema).is_empty());
+    ///
+    /// let diagnostics = rule!["=", ["+", "name", 1], 2].unwrap().typecheck(&schema);
+    /// assert_eq!(diagnostics[0].to_string(), "`+` expects args of one kind, got string and number in `+` at args[0]");
+    ///
+    /// let diagnostics = rule!["-", "age", 1].unwrap().typecheck(&schema);
+    /// assert_eq!(diagnostics[0].to_string(), "the rule returns null or number, not bool in `-`");
+    /// ```
+    pub fn typecheck(&self, schema: &Schema) -> Vec<Diagnostic> {
+        types::check(&self.expr, schema, self.options.strict)
+    }
+
+    /// Returns the context variables the rule may read, by the name or path they're referred to,
+    /// including the literal string first args which are tried as context variables unless in strict mode,
+    /// but not the local variables of `let` nor the elements bound to `$` in the lambdas of the collection operators.
+    /// The names computed at runtime, e.g. `["var", ["lower", "key"]]`, are not known.
+    ///
+    /// ```
+    /// use ::rule::{Rule, rule, json};
+    ///
+    /// let r = rule!["&&", ["=", "user.country", "DE"], [">", ["var", "/items/0/price"], 10], ["lower", ["var", "email"]]].unwrap();
+    /// assert_eq!(r.variables().into_iter().collect::<Vec<_>>(), vec!["/items/0/price", "email", "user.country"]);
+    /// assert_eq!(Rule::parse_expr("age > 18 && 'DE' = country").unwrap().variables().len(), 3);
+    /// ```
+    pub fn variables(&self) -> BTreeSet<String> {
+        let mut vars = BTreeSet::new();
+        self.expr.collect_variables(self.options.strict, &mut vars);
+        vars
+    }
+
+    /// Compiles the rule for fast evaluation against borrowed Json contexts, see [`compile`](../compile/index.html).
+    pub fn compile(&self) -> CompiledRule {
+        CompiledRule::new(&self.expr, &self.options)
+    }
+
+    /// Returns an equivalent rule simplified for evaluation:
+    /// the constant subexpressions are folded, nested `and`s and `or`s are flattened,
+    /// the constants which can't change their result are removed, and so are double negations.
+    /// The errors are kept, though they may be located at other paths.
+    ///
+    /// ```
+    /// use ::rule::{rule, json};
+    ///
+    /// let r = rule!["all", true, ["=", "a", ["+", 1, 2]], ["&&", ["!", ["!", [">", "b", 0]]], 1]].unwrap();
+    /// assert_eq!(r.optimize().to_json(), json!(["all", ["=", "a", 3], [">", "b", 0]]));
+    /// assert_eq!(rule!["||", ["=", "a", 1], ["<", 1, 2]].unwrap().optimize().to_json(), json!(["||", ["=", "a", 1], true]));
+    /// assert_eq!(rule!["&&", ["=", 1, 2], "a"].unwrap().optimize().to_json(), json!(["lit", false]));
+    /// ```
+    pub fn optimize(&self) -> Rule {
+        Rule {
+            expr: optimize::optimize(&self.expr),
+            options: self.options.clone(),
+        }
+    }
+
+    /// Converts the rule back to its Json expression, `Rule::new(r.to_json()) == r`.
+    pub fn to_json(&self) -> Json {
+        self.expr.to_json()
+    }
+
+    /// Prints the rule in the infix text syntax.
+    pub fn to_text(&self) -> String {
+        self.expr.to_text()
+    }
+
+    /// Returns the expression of the rule.
+    pub fn expr(&self) -> &Expr {
+        &self.expr
+    }
+
     /// Matches the rule with a context.
     pub fn matches<T: Serialize>(&self, context: &T) -> Result<bool> {
-        self.expr.matches(context)?.as_bool().ok_or(Error::FinalResultNotBoolError)
+        let context = to_value(context)?;
+        let ctx = EvalCtx::new(context.as_object().ok_or(Error::ContextNotDictError)?).with_options(&self.options);
+        self.expr.eval(&ctx)?.as_bool().ok_or(Error::FinalResultNotBoolError)
+    }
+
+    /// Evaluates the rule with a context and returns its value, which needs not be a bool,
+    /// e.g. a discount rate computed by `if` or `case`. A datetime is returned as an RFC 3339 string.
+    ///
+    /// ```
+    /// use ::rule::{Rule, json};
+    ///
+    /// let r = Rule::parse_expr("case(amount >= 1000, 0.2, vip, 0.1, 0)").unwrap();
+    /// assert_eq!(r.evaluate(&json!({"amount": 1500, "vip": false})).unwrap(), json!(0.2));
+    /// assert_eq!(Rule::parse_expr("upper(country)").unwrap().evaluate(&json!({"country": "de"})).unwrap(), json!("DE"));
+    /// ```
+    pub fn evaluate<T: Serialize>(&self, context: &T) -> Result<Json> {
+        let context = to_value(context)?;
+        let ctx = EvalCtx::new(context.as_object().ok_or(Error::ContextNotDictError)?).with_options(&self.options);
+        Ok(self.expr.eval(&ctx)?.to_json_context_var())
     }
 }
 
 /// The Expression type, contains a `Op` and a `Vec<Arg>`.
-#[derive(Clone, Debug, PartialEq, PartialOrd)]
+#[derive(Clone, Debug)]
 pub struct Expr {
     op: Op,
     args: Vec<Arg>,
+    span: Option<Span>,
+}
+
+// the source span is not a part of the expression
+impl PartialEq for Expr {
+    fn eq(&self, other: &Expr) -> bool {
+        self.op == other.op && self.args == other.args
+    }
+}
+
+impl PartialOrd for Expr {
+    fn partial_cmp(&self, other: &Expr) -> Option<Ordering> {
+        (&self.op, &self.args).partial_cmp(&(&other.op, &other.args))
+    }
 }
 
 impl Expr {
     /// Constructs an new `Expr` from a serde Json Value object.
     pub fn new(val: Json) -> Result<Expr> {
+        Expr::new_in(val, OpRegistry::builtin())
+    }
+
+    /// Constructs an new `Expr` from a serde Json Value object, resolves the operators in `registry`.
+    pub fn new_in(val: Json, registry: &OpRegistry) -> Result<Expr> {
         match val {
             Json::Array(args) => {
-                Expr::from_vec(args)
+                Expr::from_vec_in(args, registry)
             },
-            _ => Err(Error::ExprIsNotArrayError),
+            _ => Err(Error::ExprIsNotArrayError { path: ExprPath::default() }),
         }
     }
 
     /// Constructs an new `Expr` from a Vec of Json object.
     pub fn from_vec(val: Vec<Json>) -> Result<Expr> {
-        let mut args: Vec<Arg> = val.into_iter().map(|x| Arg::from_json(x)).collect::<Result<Vec<_>>>()?;
-        let op_s = match args.remove(0) {
-            Arg::String(s) => s,
-            _ => return Err(Error::ExprOpIsNotStringError),
+        Expr::from_vec_in(val, OpRegistry::builtin())
+    }
+
+    /// Constructs an new `Expr` from a Vec of Json object, resolves the operators in `registry`.
+    pub fn from_vec_in(val: Vec<Json>, registry: &OpRegistry) -> Result<Expr> {
+        let mut it = val.into_iter();
+        let op_s = match it.next() {
+            Some(Json::String(s)) => s,
+            _ => return Err(Error::ExprOpIsNotStringError { path: ExprPath::default() }),
         };
-        let op = match Op::get(&op_s) {
+        let op = match registry.get(&op_s) {
             Some(v) => v,
-            None => return Err(Error::NoSuchOpError),
+            None => return Err(Error::NoSuchOpError { op: op_s, path: ExprPath::default() }),
+        };
+        let args = if op.quote {
+            // args of quote op are data
+            it.map(Arg::from_json_context_var).collect::<Result<Vec<_>>>()?
+        } else {
+            it.enumerate()
+                .map(|(i, x)| Arg::from_json_in(x, registry).map_err(|err| err.in_arg(i)))
+                .collect::<Result<Vec<_>>>()?
+        };
+        op.signature.check(&op_s, &args).map_err(|message| Error::SignatureError { message, path: ExprPath::default() })?;
+        let op = op.compile(&args).map_err(|err| err.in_expr(&op_s, None))?;
+        Ok(Expr::from_parts(op, args, None))
+    }
+
+    pub(crate) fn from_parts(op: Op, args: Vec<Arg>, span: Option<Span>) -> Expr {
+        Expr { op, args, span }
+    }
+
+    /// Constructs an new `Expr` from the infix text syntax.
+    pub fn parse(s: &str) -> Result<Expr> {
+        Expr::parse_in(s, OpRegistry::builtin())
+    }
+
+    /// Constructs an new `Expr` from the infix text syntax, resolves the operators in `registry`.
+    pub fn parse_in(s: &str, registry: &OpRegistry) -> Result<Expr> {
+        text::parse(s, registry)
+    }
+
+    /// Returns the operator.
+    pub fn op(&self) -> &Op {
+        &self.op
+    }
+
+    /// Returns the arguments.
+    pub fn args(&self) -> &[Arg] {
+        &self.args
+    }
+
+    /// Returns the source span if the expression is parsed from the text syntax.
+    pub fn span(&self) -> Option<Span> {
+        self.span
+    }
+
+    /// Prints the expression in the infix text syntax.
+    pub fn to_text(&self) -> String {
+        text::print(self)
+    }
+
+    /// Converts the expression back to Json, `Expr::new(e.to_json()) == e`.
+    pub fn to_json(&self) -> Json {
+        let mut val = vec![Json::String(self.op.name.clone())];
+        if self.op.quote {
+            val.extend(self.args.iter().map(Arg::to_json_context_var));
+        } else {
+            val.extend(self.args.iter().map(Arg::to_json));
+        }
+        Json::Array(val)
+    }
+
+    /// Returns the context variables the expression may read, see `Rule::variables`.
+    pub fn variables(&self) -> BTreeSet<String> {
+        let mut vars = BTreeSet::new();
+        self.collect_variables(false, &mut vars);
+        vars
+    }
+
+    fn collect_variables(&self, strict: bool, vars: &mut BTreeSet<String>) {
+        if self.op.quote {
+            // the args are data
+            return;
+        }
+        // the name of `var` and `has` is always a variable, a literal string first arg may be one
+        let named = match self.op.builtin {
+            Some("var") => true,
+            Some("has") => self.args.len() == 1,
+            _ => false,
         };
-        Ok(Expr { op: op.clone(), args: args })
+        // the lambda args[1] of a collection operator reads the bound element, not the context,
+        // so does the body args[1] of `let` read its local variables
+        let bound: Vec<&str> = match self.op.builtin {
+            Some("reduce") => vec![op::ELEMENT, op::ACCUMULATOR],
+            Some("some") | Some("every") | Some("none") | Some("filter") | Some("map") | Some("count") => vec![op::ELEMENT],
+            Some("let") => match self.args.first() {
+                Some(Arg::Object(map)) => map.keys().map(String::as_str).collect(),
+                _ => vec![],
+            },
+            _ => vec![],
+        };
+        for (i, arg) in self.args.iter().enumerate() {
+            match arg {
+                Arg::String(name) if i == 0 && (named || !strict) => {
+                    vars.insert(name.clone());
+                },
+                arg if i == 1 && !bound.is_empty() => {
+                    let mut inner = BTreeSet::new();
+                    collect_variables(arg, strict, &mut inner);
+                    vars.extend(inner.into_iter().filter(|name| {
+                        !Path::parse(name).segments().first().is_some_and(|root| bound.contains(&root.as_str()))
+                    }));
+                },
+                arg => collect_variables(arg, strict, vars),
+            }
+        }
     }
 
     /// Matches the expression with a Serialize context.
@@ -80,22 +411,62 @@ impl Expr {
 
     /// Matches the expression with a Json Dict context.
     pub fn matches_json_dict(&self, context: &Map<String, Json>) -> Result<Arg> {
-        let mut args = self.args.iter().map(|arg|
-            if let Arg::Expr(expr) = arg { expr.matches_json_dict(context) } else { Ok(arg.clone()) }
-            ).collect::<Result<Vec<_>>>()?;
-        // println!("DEBUG: args: {:?}", args);
-        // println!("DEBUG: op: {:?}", self.op);
-
-        if &self.op.name == "var" {
-            // special op var
-            Arg::from_context_var(&args, context)
-        } else {
-            // always try first arg with context var
-            let var = Arg::from_context_var(&args, context);
-            if var.is_ok() {
-                args[0] = var?;
-            }
-            Ok((self.op.func)(args))
+        self.eval(&EvalCtx::new(context))
+    }
+
+    /// Evaluates the expression within an `EvalCtx`.
+    pub fn eval(&self, ctx: &EvalCtx) -> Result<Arg> {
+        let ctx = &ctx.with_expr(self);
+        if let Some(tracer) = ctx.tracer() {
+            tracer.enter(&self.op);
+        }
+        let result = self.eval_op(ctx).map_err(|err| err.in_expr(&self.op.name, self.span));
+        if let Some(tracer) = ctx.tracer() {
+            tracer.leave(&result);
+        }
+        result
+    }
+
+    fn eval_op(&self, ctx: &EvalCtx) -> Result<Arg> {
+        if self.op.lazy {
+            // lazy op evaluates the args itself
+            return (self.op.func)(&self.args, ctx);
+        }
+
+        let mut args = self.args.iter().map(|arg| ctx.eval(arg)).collect::<Result<Vec<_>>>()?;
+        // always try first literal string arg with context var
+        if let Some(var) = self.args.first().and_then(|arg| ctx.resolve(arg)) {
+            args[0] = var;
+        }
+        if let Some(tracer) = ctx.tracer() {
+            tracer.args(&args);
         }
+        (self.op.func)(&args, ctx)
+    }
+}
+
+fn collect_variables(arg: &Arg, strict: bool, vars: &mut BTreeSet<String>) {
+    match arg {
+        Arg::Expr(expr) => expr.collect_variables(strict, vars),
+        Arg::Object(map) => map.values().for_each(|val| collect_variables(val, strict, vars)),
+        _ => (),
+    }
+}
+
+impl Serialize for Rule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        self.to_json().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Rule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> result::Result<Rule, D::Error> {
+        Rule::new(Json::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for Expr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        self.to_json().serialize(serializer)
     }
 }
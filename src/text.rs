@@ -0,0 +1,713 @@
+//! The human-readable infix text syntax of rules.
+//!
+//! ```text
+//! age >= 18 && country in ('DE', 'FR') && !startswith(lower(email), 'test')
+//! ```
+//!
+//! It compiles to the same `Expr` tree as the Json syntax:
+//!
+//! - identifiers are context variables, e.g. `user.address.country` is `["var", "user.address.country"]`;
+//! - strings (`'DE'` or `"DE"`), numbers, `true`, `false` and `null` are literals,
+//!   like in Json rules a literal string in the first argument is tried as a context variable,
+//!   use `lit('DE')` to keep it literal;
+//! - `name(arg0, arg1, ...)` calls an operator by name, the args of `lit` are data, e.g. `lit([1, 2])`;
+//! - `{'key': expr}` is an object;
+//! - the infix and prefix operators, from the lowest precedence to the highest:
+//!   `||`, `&&`, `= == != < <= > >= in`, `+ -`, `* / %`, `! -`.
+//!
+//! Chains of the same operator are flattened, e.g. `a + b + c` is `["+", a, b, c]`
+//! and `1 < x < 10` is `["<", 1, x, 10]`.
+
+use serde_json::value::{Value as Json};
+
+use crate::arg::Arg;
+use crate::error::{Error, Result, Span};
+use crate::op::OpRegistry;
+use crate::rule::Expr;
+
+/// The infix operators, as (symbol, op name, function name, precedence).
+const INFIX: &[(&str, &str, &str, u8)] = &[
+    ("||", "||", "or", 1),
+    ("&&", "&&", "and", 2),
+    ("=", "=", "eq", 3),
+    ("!=", "!=", "ne", 3),
+    ("<", "<", "lt", 3),
+    ("<=", "<=", "le", 3),
+    (">", ">", "gt", 3),
+    (">=", ">=", "ge", 3),
+    ("in", "in", "in", 3),
+    ("+", "+", "add", 4),
+    ("-", "-", "sub", 4),
+    ("*", "*", "mul", 5),
+    ("/", "/", "div", 5),
+    ("%", "%", "rem", 5),
+];
+
+/// The precedence of the prefix operators.
+const PREFIX: u8 = 6;
+/// The precedence of literals, variables, calls and parenthesized expressions.
+const PRIMARY: u8 = 7;
+/// The max nesting depth of expressions, deeper input is an error rather than a stack overflow.
+const MAX_DEPTH: usize = 64;
+
+/// Parses an infix text expression, resolves the operators in `registry`.
+pub fn parse(s: &str, registry: &OpRegistry) -> Result<Expr> {
+    let mut parser = Parser {
+        src: s,
+        tokens: Lexer::new(s).tokenize()?,
+        pos: 0,
+        depth: 0,
+        registry,
+    };
+    let val = parser.parse_expr()?;
+    parser.expect_eof()?;
+    match val {
+        Arg::Expr(expr) => Ok(expr),
+        Arg::Object(_) => Err(syntax_error(s, 0, "expected an expression, found an object".to_owned())),
+        // a bare literal is not an expression, wrap it as `lit`
+        _ => parser.call("lit", vec![val], 0),
+    }
+}
+
+/// Prints an `Expr` in the infix text syntax.
+pub fn print(expr: &Expr) -> String {
+    let mut out = String::new();
+    print_expr(expr, &mut out);
+    out
+}
+
+fn syntax_error(src: &str, offset: usize, message: String) -> Error {
+    let span = Span::new(src, offset, offset);
+    Error::SyntaxError { line: span.line, column: span.column, message }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(Json),
+    Str(String),
+    Ident(String),
+    Punct(&'static str),
+    Eof,
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::Number(v) => format!("number `{}`", v),
+            Token::Str(v) => format!("string {:?}", v),
+            Token::Ident(v) => format!("`{}`", v),
+            Token::Punct(v) => format!("`{}`", v),
+            Token::Eof => "end of input".to_owned(),
+        }
+    }
+}
+
+const PUNCTS: &[&str] = &[
+    "||", "&&", "==", "!=", "<=", ">=",
+    "=", "<", ">", "+", "-", "*", "/", "%", "!",
+    "(", ")", "[", "]", "{", "}", ",", ":",
+];
+
+struct Lexer<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(src: &'a str) -> Lexer<'a> {
+        Lexer { src, pos: 0 }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn tokenize(mut self) -> Result<Vec<(Token, usize, usize)>> {
+        let mut tokens = vec![];
+        loop {
+            while self.peek().is_some_and(char::is_whitespace) {
+                self.pos += self.peek().map_or(0, char::len_utf8);
+            }
+            let start = self.pos;
+            let c = match self.peek() {
+                Some(c) => c,
+                None => {
+                    tokens.push((Token::Eof, start, start));
+                    return Ok(tokens);
+                },
+            };
+            let token = if c.is_ascii_digit() {
+                self.number()?
+            } else if c == '\'' || c == '"' {
+                self.string(c)?
+            } else if is_ident_start(c) {
+                self.ident()
+            } else if let Some(p) = PUNCTS.iter().find(|p| self.rest().starts_with(*p)) {
+                self.pos += p.len();
+                Token::Punct(p)
+            } else {
+                return Err(syntax_error(self.src, start, format!("unexpected character `{}`", c)));
+            };
+            tokens.push((token, start, self.pos));
+        }
+    }
+
+    fn number(&mut self) -> Result<Token> {
+        let start = self.pos;
+        let digits = |lexer: &mut Lexer| {
+            while lexer.peek().is_some_and(|c| c.is_ascii_digit()) {
+                lexer.pos += 1;
+            }
+        };
+        digits(self);
+        let mut float = false;
+        if self.rest().starts_with('.') && self.rest()[1..].starts_with(|c: char| c.is_ascii_digit()) {
+            float = true;
+            self.pos += 1;
+            digits(self);
+        }
+        if self.rest().starts_with(['e', 'E']) {
+            float = true;
+            self.pos += 1;
+            if self.rest().starts_with(['+', '-']) {
+                self.pos += 1;
+            }
+            digits(self);
+        }
+        let s = &self.src[start..self.pos];
+        let num = if float {
+            s.parse::<f64>().ok().and_then(serde_json::Number::from_f64)
+        } else {
+            // the magnitude of `i64::MIN` is lexed too, only a prefix `-` makes it a valid int, see `Parser::number`
+            s.parse::<u64>().ok().filter(|&v| v <= i64::MIN.unsigned_abs()).map(serde_json::Number::from)
+        };
+        num.map(|n| Token::Number(Json::Number(n)))
+            .ok_or_else(|| syntax_error(self.src, start, format!("invalid number `{}`", s)))
+    }
+
+    fn string(&mut self, quote: char) -> Result<Token> {
+        let start = self.pos;
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            let c = self.peek().ok_or_else(|| syntax_error(self.src, start, "unterminated string".to_owned()))?;
+            self.pos += c.len_utf8();
+            match c {
+                '\\' => {
+                    let escaped = self.peek().ok_or_else(|| syntax_error(self.src, start, "unterminated string".to_owned()))?;
+                    self.pos += escaped.len_utf8();
+                    s.push(match escaped {
+                        'n' => '\n',
+                        't' => '\t',
+                        'r' => '\r',
+                        '\\' | '\'' | '"' => escaped,
+                        _ => return Err(syntax_error(self.src, self.pos - escaped.len_utf8() - 1, format!("unknown escape `\\{}`", escaped))),
+                    });
+                },
+                _ if c == quote => return Ok(Token::Str(s)),
+                _ => s.push(c),
+            }
+        }
+    }
+
+    fn ident(&mut self) -> Token {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if is_ident_char(c) {
+                self.pos += c.len_utf8();
+            } else if c == '.' && self.rest()[1..].starts_with(is_ident_char) {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        Token::Ident(self.src[start..self.pos].to_owned())
+    }
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_' || c == '$'
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$'
+}
+
+fn is_keyword(s: &str) -> bool {
+    matches!(s, "true" | "false" | "null" | "in")
+}
+
+/// Returns true if `s` can be written as a bare identifier, e.g. `user.address.country`.
+fn is_ident(s: &str) -> bool {
+    !is_keyword(s)
+        && s.starts_with(is_ident_start)
+        && s.split('.').all(|seg| !seg.is_empty() && seg.chars().all(is_ident_char))
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    tokens: Vec<(Token, usize, usize)>,
+    pos: usize,
+    depth: usize,
+    registry: &'a OpRegistry,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.pos].0
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens[self.pos].1
+    }
+
+    /// The end offset of the last consumed token.
+    fn prev_end(&self) -> usize {
+        self.tokens[..self.pos].last().map_or(0, |(_, _, end)| *end)
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.pos].0.clone();
+        if token != Token::Eof {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn error(&self, message: String) -> Error {
+        syntax_error(self.src, self.offset(), message)
+    }
+
+    fn unexpected(&self, expected: &str) -> Error {
+        self.error(format!("expected {}, found {}", expected, self.peek().describe()))
+    }
+
+    fn eat(&mut self, punct: &str) -> bool {
+        if matches!(self.peek(), Token::Punct(p) if *p == punct) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<()> {
+        if self.eat(punct) {
+            Ok(())
+        } else {
+            Err(self.unexpected(&format!("`{}`", punct)))
+        }
+    }
+
+    fn expect_eof(&self) -> Result<()> {
+        match self.peek() {
+            Token::Eof => Ok(()),
+            _ => Err(self.unexpected("an operator or end of input")),
+        }
+    }
+
+    /// The infix operator at the current token, as (op name, precedence).
+    fn peek_infix(&self) -> Option<(&'static str, u8)> {
+        let symbol = match self.peek() {
+            Token::Punct("==") => "=",
+            Token::Punct(p) => p,
+            Token::Ident(s) if s == "in" => "in",
+            _ => return None,
+        };
+        INFIX.iter().find(|(sym, ..)| *sym == symbol).map(|(_, name, _, prec)| (*name, *prec))
+    }
+
+    /// Builds the `Expr` of op `name` spanning from `start` to the last consumed token.
+    fn call(&self, name: &str, args: Vec<Arg>, start: usize) -> Result<Expr> {
+        let op = self.registry.get(name)
+            .ok_or_else(|| syntax_error(self.src, start, format!("no such operator `{}`", name)))?;
+        op.signature.check(name, &args).map_err(|message| syntax_error(self.src, start, message))?;
+        let span = Some(Span::new(self.src, start, self.prev_end()));
+        let op = op.compile(&args).map_err(|err| err.in_expr(name, span))?;
+        Ok(Expr::from_parts(op, args, span))
+    }
+
+    /// Parses with `f` one level deeper, fails if it's nested too deeply instead of overflowing the stack.
+    fn nested<T>(&mut self, f: impl FnOnce(&mut Parser<'a>) -> Result<T>) -> Result<T> {
+        if self.depth >= MAX_DEPTH {
+            return Err(self.error("expression nested too deeply".to_owned()));
+        }
+        self.depth += 1;
+        let val = f(self);
+        self.depth -= 1;
+        val
+    }
+
+    fn parse_expr(&mut self) -> Result<Arg> {
+        self.nested(|p| p.parse_infix(1))
+    }
+
+    /// Parses the infix operators of precedence `min_prec` and higher, by precedence climbing.
+    fn parse_infix(&mut self, min_prec: u8) -> Result<Arg> {
+        let start = self.offset();
+        let mut lhs = self.parse_prefix()?;
+        while let Some((name, prec)) = self.peek_infix() {
+            if prec < min_prec {
+                break;
+            }
+            let mut args = vec![lhs];
+            while self.peek_infix().map(|(n, _)| n) == Some(name) {
+                let offset = self.offset();
+                self.next();
+                if name == "in" {
+                    args.extend(self.parse_list(offset)?);
+                    break;
+                } else {
+                    args.push(self.parse_infix(prec + 1)?);
+                }
+            }
+            if prec == 3 && self.peek_infix().is_some_and(|(_, p)| p == 3) {
+                return Err(self.error("comparison operators cannot be chained with each other, use parentheses".to_owned()));
+            }
+            lhs = Arg::Expr(self.call(name, args, start)?);
+        }
+        Ok(lhs)
+    }
+
+    /// Parses the `(a, b, ...)` after `in`.
+    fn parse_list(&mut self, offset: usize) -> Result<Vec<Arg>> {
+        if !self.eat("(") {
+            return Err(syntax_error(self.src, offset, "expected a parenthesized list after `in`".to_owned()));
+        }
+        self.parse_args(")", Parser::parse_expr)
+    }
+
+    fn parse_args<T>(&mut self, close: &str, item: fn(&mut Parser<'a>) -> Result<T>) -> Result<Vec<T>> {
+        let mut args = vec![];
+        if self.eat(close) {
+            return Ok(args);
+        }
+        loop {
+            args.push(item(self)?);
+            if self.eat(close) {
+                return Ok(args);
+            }
+            if !self.eat(",") {
+                return Err(self.unexpected(&format!("`,` or `{}`", close)));
+            }
+        }
+    }
+
+    fn parse_prefix(&mut self) -> Result<Arg> {
+        let start = self.offset();
+        if self.eat("!") {
+            let arg = self.nested(Parser::parse_prefix)?;
+            return Ok(Arg::Expr(self.call("!", vec![arg], start)?));
+        }
+        if self.eat("-") {
+            if matches!(self.peek(), Token::Number(n) if n.as_u64() == Some(i64::MIN.unsigned_abs())) {
+                self.next();
+                return Ok(Arg::Int(i64::MIN));
+            }
+            return Ok(match self.nested(Parser::parse_prefix)? {
+                Arg::Int(v) => Arg::Int(-v),
+                Arg::Float(v) => Arg::Float(-v),
+                arg => Arg::Expr(self.call("neg", vec![arg], start)?),
+            });
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Arg> {
+        let offset = self.offset();
+        match self.next() {
+            Token::Number(n) => Arg::from_json_context_var(self.number(n, offset)?),
+            Token::Str(s) => Ok(Arg::String(s)),
+            Token::Ident(s) => match s.as_str() {
+                "true" => Ok(Arg::Bool(true)),
+                "false" => Ok(Arg::Bool(false)),
+                "null" => Ok(Arg::Null),
+                "in" => Err(syntax_error(self.src, offset, "expected an expression, found `in`".to_owned())),
+                _ if self.eat("(") => self.parse_call(s, offset),
+                _ => Ok(Arg::Expr(self.call("var", vec![Arg::String(s)], offset)?)),
+            },
+            Token::Punct("(") => {
+                let val = self.parse_expr()?;
+                self.expect(")")?;
+                Ok(val)
+            },
+            Token::Punct("{") => Ok(Arg::Object(self.parse_object(Parser::parse_expr)?.into_iter().collect())),
+            Token::Punct("[") => Err(syntax_error(self.src, offset, "arrays are data, use `lit([...])`".to_owned())),
+            token => Err(syntax_error(self.src, offset, format!("expected an expression, found {}", token.describe()))),
+        }
+    }
+
+    fn parse_call(&mut self, name: String, offset: usize) -> Result<Arg> {
+        let op = self.registry.get(&name)
+            .ok_or_else(|| syntax_error(self.src, offset, format!("no such operator `{}`", name)))?;
+        let args = if op.quote {
+            self.parse_args(")", Parser::parse_data)?.into_iter().map(Arg::from_json_context_var).collect::<Result<_>>()?
+        } else {
+            self.parse_args(")", Parser::parse_expr)?
+        };
+        Ok(Arg::Expr(self.call(&name, args, offset)?))
+    }
+
+    fn parse_object<T>(&mut self, value: fn(&mut Parser<'a>) -> Result<T>) -> Result<Vec<(String, T)>> {
+        let mut fields = vec![];
+        if self.eat("}") {
+            return Ok(fields);
+        }
+        loop {
+            let key = match self.peek() {
+                Token::Str(s) | Token::Ident(s) => s.clone(),
+                _ => return Err(self.unexpected("an object key")),
+            };
+            self.next();
+            self.expect(":")?;
+            fields.push((key, value(self)?));
+            if self.eat("}") {
+                return Ok(fields);
+            }
+            if !self.eat(",") {
+                return Err(self.unexpected("`,` or `}`"));
+            }
+        }
+    }
+
+    /// Checks a number not negated by a prefix `-`, the magnitude of `i64::MIN` is out of range.
+    fn number(&self, n: Json, offset: usize) -> Result<Json> {
+        match n.as_u64() {
+            Some(v) if v > i64::MAX as u64 => Err(syntax_error(self.src, offset, format!("invalid number `{}`", n))),
+            _ => Ok(n),
+        }
+    }
+
+    /// Parses a data literal, the arg of `lit`.
+    fn parse_data(&mut self) -> Result<Json> {
+        let offset = self.offset();
+        match self.next() {
+            Token::Number(n) => self.number(n, offset),
+            Token::Str(s) => Ok(Json::String(s)),
+            Token::Ident(ref s) if s == "true" => Ok(Json::Bool(true)),
+            Token::Ident(ref s) if s == "false" => Ok(Json::Bool(false)),
+            Token::Ident(ref s) if s == "null" => Ok(Json::Null),
+            Token::Punct("-") => match self.next() {
+                Token::Number(n) => Ok(negate(n)),
+                _ => Err(syntax_error(self.src, offset, "expected a number after `-`".to_owned())),
+            },
+            Token::Punct("[") => Ok(Json::Array(self.nested(|p| p.parse_args("]", Parser::parse_data))?)),
+            Token::Punct("{") => Ok(Json::Object(self.nested(|p| p.parse_object(Parser::parse_data))?.into_iter().collect())),
+            token => Err(syntax_error(self.src, offset, format!("expected a literal, found {}", token.describe()))),
+        }
+    }
+}
+
+fn negate(val: Json) -> Json {
+    match (val.as_i64(), val.as_f64()) {
+        (Some(i), _) => Json::from(-i),
+        (None, _) if val.as_u64() == Some(i64::MIN.unsigned_abs()) => Json::from(i64::MIN),
+        (None, Some(f)) => Json::from(-f),
+        _ => val,
+    }
+}
+
+/// The infix symbol and precedence of an op name, `None` if it's not an infix op.
+fn infix(name: &str) -> Option<(&'static str, u8)> {
+    let name = match name {
+        "eq" => "=",
+        "ne" => "!=",
+        "lt" => "<",
+        "le" => "<=",
+        "gt" => ">",
+        "ge" => ">=",
+        "&" | "all" | "and" => "&&",
+        "|" | "any" | "or" => "||",
+        "add" | "sum" => "+",
+        "sub" | "minus" => "-",
+        "mul" => "*",
+        "div" => "/",
+        "rem" | "mod" => "%",
+        _ => name,
+    };
+    INFIX.iter().find(|(_, n, ..)| *n == name).map(|(sym, _, _, prec)| (*sym, *prec))
+}
+
+/// The function name to call an infix op with, e.g. `add(x)` for `["+", x]`.
+fn func_name(name: &str) -> &str {
+    INFIX.iter().find(|(_, n, ..)| *n == name).map_or(name, |(_, _, func, _)| *func)
+}
+
+fn precedence(arg: &Arg) -> u8 {
+    match arg {
+        Arg::Expr(expr) => expr_precedence(expr),
+        Arg::Int(v) if *v < 0 => PREFIX,
+        Arg::Float(v) if *v < 0.0 => PREFIX,
+        _ => PRIMARY,
+    }
+}
+
+fn expr_precedence(expr: &Expr) -> u8 {
+    let name = expr.op().name.as_str();
+    let args = expr.args();
+    match name {
+        "!" | "not" if args.len() == 1 => PREFIX,
+        "neg" if args.len() == 1 && !is_number(&args[0]) => PREFIX,
+        "in" if !args.is_empty() => 3,
+        _ => match infix(name) {
+            Some((_, prec)) if args.len() >= 2 => prec,
+            _ => PRIMARY,
+        },
+    }
+}
+
+fn is_number(arg: &Arg) -> bool {
+    matches!(arg, Arg::Int(_) | Arg::Float(_))
+}
+
+fn print_wrapped(arg: &Arg, wrap: bool, out: &mut String) {
+    if wrap {
+        out.push('(');
+    }
+    print_arg(arg, out);
+    if wrap {
+        out.push(')');
+    }
+}
+
+fn print_expr(expr: &Expr, out: &mut String) {
+    let name = expr.op().name.as_str();
+    let args = expr.args();
+    let prec = expr_precedence(expr);
+    match name {
+        "var" if args.len() == 1 && args[0].as_str().is_some_and(is_ident) => {
+            out.push_str(args[0].as_str().unwrap_or(""));
+        },
+        _ if prec == PREFIX => {
+            out.push(if name == "neg" { '-' } else { '!' });
+            print_wrapped(&args[0], precedence(&args[0]) < PREFIX, out);
+        },
+        "in" if prec == 3 => {
+            print_wrapped(&args[0], precedence(&args[0]) <= 3, out);
+            out.push_str(" in (");
+            print_list(&args[1..], out);
+            out.push(')');
+        },
+        _ if prec < PREFIX => {
+            let (symbol, _) = infix(name).unwrap_or(("", 0));
+            for (i, arg) in args.iter().enumerate() {
+                if i > 0 {
+                    out.push(' ');
+                    out.push_str(symbol);
+                    out.push(' ');
+                }
+                // the first arg can be another op of the same precedence, except for the non-associative comparisons
+                let child = precedence(arg);
+                let same_op = matches!(arg, Arg::Expr(e) if infix(&e.op().name) == infix(name));
+                let wrap = if i == 0 && prec != 3 { child < prec || same_op } else { child <= prec };
+                print_wrapped(arg, wrap, out);
+            }
+        },
+        _ => {
+            out.push_str(func_name(name));
+            out.push('(');
+            if expr.op().quote {
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    print_data(arg, out);
+                }
+            } else {
+                print_list(args, out);
+            }
+            out.push(')');
+        },
+    }
+}
+
+fn print_list(args: &[Arg], out: &mut String) {
+    for (i, arg) in args.iter().enumerate() {
+        if i > 0 {
+            out.push_str(", ");
+        }
+        print_arg(arg, out);
+    }
+}
+
+fn print_arg(arg: &Arg, out: &mut String) {
+    match arg {
+        Arg::Expr(expr) => print_expr(expr, out),
+        Arg::Array(_) => {
+            out.push_str("lit(");
+            print_data(arg, out);
+            out.push(')');
+        },
+        Arg::Object(map) => {
+            out.push('{');
+            for (i, (k, v)) in map.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                print_string(k, out);
+                out.push_str(": ");
+                print_arg(v, out);
+            }
+            out.push('}');
+        },
+        Arg::DateTime(_) => {
+            out.push_str("datetime(");
+            print_data(arg, out);
+            out.push(')');
+        },
+        _ => print_data(arg, out),
+    }
+}
+
+fn print_data(arg: &Arg, out: &mut String) {
+    match arg {
+        Arg::Null => out.push_str("null"),
+        Arg::Bool(v) => out.push_str(&v.to_string()),
+        Arg::Int(v) => out.push_str(&v.to_string()),
+        Arg::Float(v) => out.push_str(&format!("{:?}", v)),
+        Arg::String(v) => print_string(v, out),
+        Arg::DateTime(_) => print_string(&arg.to_string(), out),
+        Arg::Array(v) => {
+            out.push('[');
+            for (i, item) in v.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                print_data(item, out);
+            }
+            out.push(']');
+        },
+        Arg::Object(map) => {
+            out.push('{');
+            for (i, (k, v)) in map.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                print_string(k, out);
+                out.push_str(": ");
+                print_data(v, out);
+            }
+            out.push('}');
+        },
+        Arg::Expr(expr) => print_expr(expr, out),
+    }
+}
+
+fn print_string(s: &str, out: &mut String) {
+    out.push('\'');
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\'' => out.push_str("\\'"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            _ => out.push(c),
+        }
+    }
+    out.push('\'');
+}
@@ -0,0 +1,175 @@
+use std::fmt;
+use std::result;
+use std::cell::RefCell;
+
+use serde::{Serialize, Serializer};
+use serde_json::value::{Value as Json};
+use serde_json::Map;
+
+use crate::arg::Arg;
+use crate::op::Op;
+use crate::error::Result;
+
+/// The evaluation trace of an `Expr`, mirrors the structure of the expression, see `Rule::explain`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trace {
+    /// The operator name.
+    pub op: String,
+    /// The resolved args the operator is called with,
+    /// for a lazy operator, the args it has evaluated, in order.
+    pub args: Vec<Arg>,
+    /// The context variables read by this node, `None` if the variable is missing.
+    pub vars: Vec<(String, Option<Arg>)>,
+    /// The result, `None` if the evaluation failed.
+    pub result: Option<Arg>,
+    /// The error message if the evaluation failed.
+    pub error: Option<String>,
+    /// The traces of the sub-expressions evaluated, in order.
+    pub children: Vec<Trace>,
+    lazy: bool,
+}
+
+impl Trace {
+    fn new(op: &Op) -> Trace {
+        Trace {
+            op: op.name.clone(),
+            args: vec![],
+            vars: vec![],
+            result: None,
+            error: None,
+            children: vec![],
+            lazy: op.lazy,
+        }
+    }
+
+    /// Converts the trace to Json, e.g. for a dashboard.
+    ///
+    /// ```
+    /// use ::rule::{rule, json};
+    ///
+    /// let trace = rule!["=", "a", 1].unwrap().explain(&json!({"a": 1})).unwrap();
+    /// assert_eq!(trace.to_json(), json!({
+    ///     "op": "=",
+    ///     "args": [1, 1],
+    ///     "vars": [{"name": "a", "value": 1}],
+    ///     "result": true,
+    ///     "children": [],
+    /// }));
+    /// ```
+    pub fn to_json(&self) -> Json {
+        let mut val = Map::new();
+        val.insert("op".to_owned(), Json::String(self.op.clone()));
+        val.insert("args".to_owned(), Json::Array(self.args.iter().map(Arg::to_json_context_var).collect()));
+        val.insert("vars".to_owned(), Json::Array(self.vars.iter().map(|(name, value)| {
+            let mut var = Map::new();
+            var.insert("name".to_owned(), Json::String(name.clone()));
+            if let Some(value) = value {
+                var.insert("value".to_owned(), value.to_json_context_var());
+            }
+            Json::Object(var)
+        }).collect()));
+        if let Some(result) = &self.result {
+            val.insert("result".to_owned(), result.to_json_context_var());
+        }
+        if let Some(error) = &self.error {
+            val.insert("error".to_owned(), Json::String(error.clone()));
+        }
+        val.insert("children".to_owned(), Json::Array(self.children.iter().map(Trace::to_json).collect()));
+        Json::Object(val)
+    }
+
+    fn fmt_indent(&self, f: &mut fmt::Formatter<'_>, indent: usize) -> fmt::Result {
+        let pad = "  ".repeat(indent);
+        let args = self.args.iter().map(|arg| arg.to_json_context_var().to_string()).collect::<Vec<_>>();
+        write!(f, "{}{}({})", pad, self.op, args.join(", "))?;
+        match (&self.result, &self.error) {
+            (Some(result), _) => writeln!(f, " => {}", result.to_json_context_var())?,
+            (None, Some(error)) => writeln!(f, " => error: {}", error)?,
+            (None, None) => writeln!(f)?,
+        }
+        for (name, value) in &self.vars {
+            match value {
+                Some(value) => writeln!(f, "{}  | {} = {}", pad, name, value.to_json_context_var())?,
+                None => writeln!(f, "{}  | {} is missing", pad, name)?,
+            }
+        }
+        for child in &self.children {
+            child.fmt_indent(f, indent + 1)?;
+        }
+        Ok(())
+    }
+}
+
+/// Renders the trace as indented text, one line per node, e.g.
+///
+/// ```text
+/// &&(true, true) => true
+///   =(1, 1) => true
+///     | a = 1
+///   in("DE", "DE", "FR") => true
+///     | country = "DE"
+/// ```
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_indent(f, 0)
+    }
+}
+
+impl Serialize for Trace {
+    fn serialize<S: Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        self.to_json().serialize(serializer)
+    }
+}
+
+/// Records the `Trace` while evaluating.
+#[derive(Debug, Default)]
+pub(crate) struct Tracer {
+    stack: RefCell<Vec<Trace>>,
+    root: RefCell<Option<Trace>>,
+}
+
+impl Tracer {
+    pub(crate) fn enter(&self, op: &Op) {
+        self.stack.borrow_mut().push(Trace::new(op));
+    }
+
+    pub(crate) fn leave(&self, result: &Result<Arg>) {
+        let mut stack = self.stack.borrow_mut();
+        if let Some(mut trace) = stack.pop() {
+            match result {
+                Ok(v) => trace.result = Some(v.clone()),
+                Err(e) => trace.error = Some(e.to_string()),
+            }
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(trace),
+                None => *self.root.borrow_mut() = Some(trace),
+            }
+        }
+    }
+
+    /// Sets the args of the current eager node.
+    pub(crate) fn args(&self, args: &[Arg]) {
+        if let Some(trace) = self.stack.borrow_mut().last_mut() {
+            trace.args = args.to_vec();
+        }
+    }
+
+    /// Adds an arg evaluated by the current lazy node.
+    pub(crate) fn lazy_arg(&self, arg: &Arg) {
+        if let Some(trace) = self.stack.borrow_mut().last_mut() {
+            if trace.lazy {
+                trace.args.push(arg.clone());
+            }
+        }
+    }
+
+    pub(crate) fn var(&self, name: &str, value: Option<&Arg>) {
+        if let Some(trace) = self.stack.borrow_mut().last_mut() {
+            trace.vars.push((name.to_owned(), value.cloned()));
+        }
+    }
+
+    pub(crate) fn finish(self) -> Option<Trace> {
+        self.root.into_inner()
+    }
+}
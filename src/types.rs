@@ -0,0 +1,192 @@
+//! Static type checking of rules, see `Rule::typecheck`.
+//!
+//! The kind of every expression is inferred from the signatures of the operators
+//! and a `Schema` of the context variables, the conflicts are reported as `Diagnostic`s.
+
+use std::fmt;
+use std::collections::HashMap;
+
+use crate::arg::Arg;
+use crate::op::Kind;
+use crate::path::Path;
+use crate::rule::Expr;
+use crate::error::{ExprPath, Location};
+
+/// The kinds of the context variables, by the name they're referred to in rules.
+/// The variables not in the schema can be of any kind.
+///
+/// ```
+/// use ::rule::op::Kind;
+/// use ::rule::types::Schema;
+///
+/// let schema = Schema::new().var("name", Kind::STRING).var("age", Kind::NUMBER | Kind::NULL);
+/// assert_eq!(schema.get("age"), Some(Kind::NUMBER | Kind::NULL));
+/// assert_eq!(schema.get("email"), None);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Schema {
+    vars: HashMap<String, Kind>,
+}
+
+impl Schema {
+    /// Constructs an empty schema.
+    pub fn new() -> Schema {
+        Schema::default()
+    }
+
+    /// Adds the variable `name` of `kind`.
+    pub fn var(mut self, name: &str, kind: Kind) -> Schema {
+        self.insert(name, kind);
+        self
+    }
+
+    /// Adds the variable `name` of `kind`, replaces the existing one.
+    pub fn insert(&mut self, name: &str, kind: Kind) -> &mut Schema {
+        self.vars.insert(name.to_owned(), kind);
+        self
+    }
+
+    /// Returns the kind of the variable `name`, `None` if it's not in the schema.
+    pub fn get(&self, name: &str) -> Option<Kind> {
+        self.vars.get(name).copied()
+    }
+}
+
+/// A type conflict found by `Rule::typecheck`, located at the offending expression.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub at: Location,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.message, self.at)
+    }
+}
+
+/// Infers the kinds of `expr` and its sub-expressions, and collects the conflicts.
+pub(crate) fn check(expr: &Expr, schema: &Schema, strict: bool) -> Vec<Diagnostic> {
+    let mut checker = Checker { schema, strict, path: vec![], locals: vec![], diagnostics: vec![] };
+    let kind = checker.infer_expr(expr);
+    if !kind.intersects(Kind::BOOL) {
+        checker.report(expr, format!("the rule returns {}, not bool", kind));
+    }
+    checker.diagnostics
+}
+
+struct Checker<'a> {
+    schema: &'a Schema,
+    strict: bool,
+    path: Vec<usize>,
+    /// The local variables of the enclosing `let`s, the innermost last.
+    locals: Vec<(String, Kind)>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Checker<'a> {
+    fn report(&mut self, expr: &Expr, message: String) {
+        let at = Location { op: expr.op().name.clone(), path: ExprPath(self.path.clone()), span: expr.span() };
+        self.diagnostics.push(Diagnostic { message, at });
+    }
+
+    /// The kind of a variable, a local one shadows the context one,
+    /// any if it's a path into a local one or not in the schema.
+    fn var(&self, name: &str) -> Kind {
+        let path = Path::parse(name);
+        if let Some(root) = path.segments().first() {
+            if let Some((_, kind)) = self.locals.iter().rev().find(|(local, _)| local == root) {
+                return if path.segments().len() == 1 { *kind } else { Kind::ANY };
+            }
+        }
+        self.schema.get(name).unwrap_or(Kind::ANY)
+    }
+
+    fn infer_expr(&mut self, expr: &Expr) -> Kind {
+        let op = expr.op();
+        let args = expr.args();
+        if op.quote {
+            // the args are data
+            return args.first().map_or(Kind::ANY, Kind::of);
+        }
+        // a literal string first arg is resolved as a context variable, except the name of `var`
+        let resolve = !self.strict && op.builtin != Some("var");
+        let locals = self.locals.len();
+        let kinds: Vec<Kind> = args.iter().enumerate().map(|(i, arg)| {
+            self.path.push(i);
+            let kind = match arg {
+                Arg::String(name) if i == 0 && resolve => self.var(name),
+                // the local variables of `let` are in scope of its body only
+                Arg::Object(map) if i == 0 && op.builtin == Some("let") => {
+                    let vars: Vec<_> = map.iter().map(|(name, val)| (name.clone(), self.infer_arg(val))).collect();
+                    self.locals.extend(vars);
+                    Kind::OBJECT
+                },
+                arg => self.infer_arg(arg),
+            };
+            self.path.pop();
+            kind
+        }).collect();
+        self.locals.truncate(locals);
+
+        let sig = &op.signature;
+        for (i, kind) in kinds.iter().enumerate() {
+            if let Err(message) = sig.check_arg(&op.name, i, *kind) {
+                self.report(expr, message);
+            }
+        }
+        if let Err(message) = sig.check_uniform(&op.name, &kinds) {
+            self.report(expr, message);
+        }
+
+        if op.builtin == Some("var") {
+            let kind = match args.first() {
+                Some(Arg::String(name)) => self.var(name),
+                _ => Kind::ANY,
+            };
+            // the default value if the variable is missing
+            return kinds.get(1).map_or(kind, |default| kind | *default);
+        }
+        if let Some(kind) = value_kind(op.builtin, &kinds) {
+            return kind;
+        }
+        sig.ret
+    }
+
+    fn infer_arg(&mut self, arg: &Arg) -> Kind {
+        match arg {
+            Arg::Expr(expr) => self.infer_expr(expr),
+            Arg::Object(map) => {
+                for val in map.values() {
+                    self.infer_arg(val);
+                }
+                Kind::OBJECT
+            },
+            arg => Kind::of(arg),
+        }
+    }
+}
+
+/// The kind of a value-producing operator given the kinds of its args, it's one of the values it may return.
+fn value_kind(builtin: Option<&str>, kinds: &[Kind]) -> Option<Kind> {
+    // a conditional operator returns one of the values after the conditions, or the default, or null
+    let values = |first: usize| {
+        let default = match kinds.len().saturating_sub(first) % 2 {
+            1 => kinds[kinds.len() - 1],
+            _ => Kind::NULL,
+        };
+        kinds.iter().skip(first + 1).step_by(2).fold(default, |kind, k| kind | *k)
+    };
+    match builtin {
+        // the value of the body
+        Some("let") => Some(kinds.get(1).copied().unwrap_or(Kind::ANY)),
+        Some("if") | Some("case") => Some(values(0)),
+        Some("switch") => Some(values(1)),
+        // null only if all args may be null
+        Some("coalesce") => {
+            let kind = kinds.iter().fold(Kind::NULL, |kind, k| kind | *k);
+            Some(if kinds.iter().all(|k| k.intersects(Kind::NULL)) { kind } else { kind.without(Kind::NULL) })
+        },
+        _ => None,
+    }
+}
@@ -1,9 +1,19 @@
+#![allow(clippy::bool_assert_comparison)]
+
 #[macro_use]
 extern crate rule;
 
-use serde::Serialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde::{Serialize, Deserialize};
 
-use rule::{Rule, Result};
+use rule::{Rule, Result, Error};
+use rule::arg::Arg;
+use rule::op::OpRegistry;
+use rule::ctx::{EvalCtx, Options, MissingVar};
 
 // #[cfg(test)]
 // mod tests {
@@ -124,12 +134,155 @@ fn rule_match_collection_op() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn rule_match_lambda_op() -> Result<()> {
+    let context = json!({
+        "items": [{"sku": "A1", "price": 30, "qty": 2}, {"sku": "B7", "price": 120, "qty": 1}],
+        "orders": [{"id": 1, "items": [{"price": 10}]}, {"id": 2, "items": [{"price": 150}, {"price": 5}]}],
+        "tags": ["vip", "beta"],
+        "$": "not the element",
+    });
+
+    // any line item has price > 100
+    assert!(rule!["some", "items", [">", ["var", "$.price"], 100]]?.matches(&context)?);
+    assert!(Rule::parse_expr("some(items, $.price > 100) && every(items, $.qty >= 1) && none(tags, $ = 'blocked')")?.matches(&context)?);
+    assert!(!rule!["every", "items", [">", "$.price", 100]]?.matches(&context)?);
+    assert!(rule!["=", ["count", "items", ["<", "$.price", 100]], 1]?.matches(&context)?);
+    assert!(rule!["=", ["map", ["filter", "items", [">=", "$.qty", 2]], ["var", "$.sku"]], ["lit", ["A1"]]]?.matches(&context)?);
+    assert!(rule!["=", ["reduce", "items", ["+", ["var", "$acc"], ["*", "$.price", ["var", "$.qty"]]], 0], 180]?.matches(&context)?);
+
+    // an inner lambda shadows `$`, outside of the lambdas it's a context variable
+    let r = Rule::parse_expr("filter(orders, some($.items, $.price > 100)) = lit([{'id': 2, 'items': [{'price': 150}, {'price': 5}]}])")?;
+    assert!(r.matches(&context)?);
+    assert!(rule!["=", ["var", "$"], "not the element"]?.matches(&context)?);
+    assert_eq!(r.variables().into_iter().collect::<Vec<_>>(), vec!["orders"]);
+    let r = rule!["some", "items", ["&&", ["=", "$.sku", ["var", "sku"]], ["=", ["var", "$acc"], 1]]]?;
+    assert_eq!(r.variables().into_iter().collect::<Vec<_>>(), vec!["$acc", "items", "sku"]);
+
+    // a missing collection is empty with `MissingVar::Null`, other kinds are a type error
+    let r = rule!["every", ["var", "missing"], false]?.with_options(Options { missing_var: MissingVar::Null, ..Options::default() });
+    assert!(r.matches(&context)?);
+    let err = rule!["some", "tags", ["=", ["num", "$"], 1]]?.matches(&context).unwrap_err();
+    assert_eq!(err.to_string(), "invalid argument type in `num` at args[1].args[0]");
+    let err = Rule::parse_expr("count(tags) = 2 && some(orders.0, true)")?.matches(&context).unwrap_err();
+    assert_eq!(err.to_string(), "invalid argument type in `some` at args[1] (line 1, column 20)");
+
+    // the compiled and the optimized rules evaluate the lambdas like the interpreter
+    let r = Rule::parse_expr("some(items, $.price * 2 > 100 + 100)")?;
+    assert!(r.compile().matches(&context)? && r.optimize().matches(&context)?);
+    assert_eq!(r.optimize().to_text(), "some(items, $.price * 2 > 200)");
+    Ok(())
+}
+
+#[test]
+fn rule_match_let_op() -> Result<()> {
+    use rule::op::Kind;
+    use rule::types::Schema;
+
+    let context = json!({"email": "Tom@Corp.com", "domain": "corp.com", "user": {"age": 20}});
+
+    // the subexpression is written and evaluated once
+    let r = rule!["let", {"email": ["lower", ["var", "email"]]},
+        ["||", ["=", "email", "tom@example.com"], ["endswith", "email", ["join", "", "@", ["var", "domain"]]]]]?;
+    assert!(r.matches(&context)?);
+    let r = Rule::parse_expr("let({e: lower(email), u: user}, e = 'tom@corp.com' && u.age > 18)")?;
+    assert!(r.matches(&context)?);
+    assert_eq!(Rule::parse_expr(&r.to_text())?, r);
+
+    // the values are evaluated in the outer scope, the inner `let` shadows the outer one
+    assert!(rule!["let", {"email": 1, "x": ["var", "email"]}, ["=", ["var", "x"], "Tom@Corp.com"]]?.matches(&context)?);
+    assert!(rule!["let", {"x": 1}, ["let", {"x": ["+", ["var", "x"], 1]}, ["=", ["var", "x"], 2]]]?.matches(&context)?);
+    assert!(rule!["let", {"x": 1}, ["some", ["lit", [1, 2]], ["=", ["var", "$"], ["var", "x"]]]]?.matches(&context)?);
+
+    // only the names read from the context are variables
+    let r = rule!["let", {"e": ["lower", "email"], "n": ["var", "user.name"]}, ["&&", ["=", "e", "x"], ["=", ["var", "n"], ["var", "domain"]]]]?;
+    assert_eq!(r.variables().into_iter().collect::<Vec<_>>(), vec!["domain", "email", "user.name"]);
+
+    // typecheck knows the kinds of the local variables
+    let schema = Schema::new().var("email", Kind::STRING).var("age", Kind::NUMBER);
+    assert!(rule!["let", {"age": ["lower", "email"]}, ["startswith", "age", "t"]]?.typecheck(&schema).is_empty());
+    let diagnostics = rule!["let", {"e": ["lower", "email"]}, [">", ["var", "e"], 1]]?.typecheck(&schema);
+    assert_eq!(diagnostics[0].to_string(), "`>` expects args of one kind, got string and number in `>` at args[1]");
+
+    // errors are located in the values and the body
+    let err = rule!["let", {"n": ["num", "email"]}, true]?.matches(&context).unwrap_err();
+    assert_eq!(err.to_string(), "invalid argument type in `num` at args[0]");
+    let err = rule!["let", {"n": 1}, ["=", ["var", "m"], 1]]?.matches(&context).unwrap_err();
+    assert_eq!(err.to_string(), "no such context variable `m` in `var` at args[1].args[0]");
+
+    // the compiled and the optimized rules bind the variables like the interpreter
+    let r = Rule::parse_expr("let({e: lower(email), age: user.age}, e = 'tom@corp.com' && age > 10 + 8)")?;
+    assert!(r.compile().matches(&context)? && r.optimize().matches(&context)?);
+    assert_eq!(r.optimize().to_text(), "let({'age': user.age, 'e': lower(email)}, e = 'tom@corp.com' && age > 18)");
+    Ok(())
+}
+
+#[test]
+fn rule_evaluate_conditional_op() -> Result<()> {
+    use rule::op::Kind;
+    use rule::types::Schema;
+
+    let context = json!({"amount": 150, "vip": true, "country": "US", "nickname": null, "name": "tom"});
+
+    // discount rates and routing keys
+    let discount = rule!["case", [">=", "amount", 1000], 0.2, ["&&", ["var", "vip"], [">=", ["var", "amount"], 100]], 0.1, 0]?;
+    assert_eq!(discount.evaluate(&context)?, json!(0.1));
+    assert_eq!(discount.evaluate(&json!({"amount": 150, "vip": false}))?, json!(0));
+    assert_eq!(Rule::parse_expr("if(amount > 100, 0.9 * amount, amount)")?.evaluate(&context)?, json!(135.0));
+    let route = Rule::parse_expr("switch(country, 'DE', 'eu-central', 'US', 'us-east', 'eu-west')")?;
+    assert_eq!(route.evaluate(&context)?, json!("us-east"));
+    assert_eq!(route.evaluate(&json!({"country": "JP"}))?, json!("eu-west"));
+    assert_eq!(Rule::parse_expr(&route.to_text())?, route);
+    assert_eq!(rule!["switch", "amount", 100, "a", 150.0, "b"]?.evaluate(&context)?, json!("b"));
+    assert_eq!(rule!["case", ["var", "missing", false], 1]?.evaluate(&context)?, json!(null));
+
+    // coalesce returns the first non-null, a missing variable is null with `MissingVar::Null`
+    let r = rule!["coalesce", ["var", "nickname"], ["var", "alias"], ["var", "name"]]?;
+    assert!(r.evaluate(&context).is_err());
+    let r = r.with_options(Options { missing_var: MissingVar::Null, ..Options::default() });
+    assert_eq!(r.evaluate(&context)?, json!("tom"));
+    assert_eq!(r.evaluate(&json!({}))?, json!(null));
+
+    // only the chosen branch is evaluated
+    assert_eq!(rule!["if", false, ["num", "name"], ["lit", [1, 2]]]?.evaluate(&context)?, json!([1, 2]));
+    let err = rule!["if", true, ["num", "name"]]?.evaluate(&context).unwrap_err();
+    assert_eq!(err.to_string(), "invalid argument type in `num` at args[1]");
+
+    // any value can be evaluated, but `matches` demands a bool
+    assert_eq!(rule!["split", "name", ","]?.evaluate(&json!({"name": "a,b"}))?, json!(["a", "b"]));
+    assert_eq!(rule!["datetime", 0]?.evaluate(&context)?, json!("1970-01-01T00:00:00Z"));
+    assert!(matches!(rule!["if", "vip", 1, 0]?.matches(&context), Err(Error::FinalResultNotBoolError)));
+    assert!(rule!["=", ["coalesce", "nickname", ["var", "name"]], "tom"]?.matches(&context)?);
+
+    // the compiled rule evaluates the same value
+    assert_eq!(discount.compile().evaluate(&context)?, json!(0.1));
+    assert_eq!(route.optimize().evaluate(&context)?, json!("us-east"));
+
+    // typecheck infers the kinds of the values
+    let schema = Schema::new().var("vip", Kind::BOOL).var("name", Kind::STRING | Kind::NULL);
+    assert_eq!(rule!["if", "vip", 0.1, 0]?.typecheck(&schema)[0].to_string(), "the rule returns number, not bool in `if`");
+    assert_eq!(rule!["case", "vip", 0.1]?.typecheck(&schema)[0].to_string(), "the rule returns null or number, not bool in `case`");
+    assert_eq!(rule!["coalesce", "name", ""]?.typecheck(&schema)[0].to_string(), "the rule returns string, not bool in `coalesce`");
+    assert!(rule!["=", ["switch", "name", "a", 1, 2], 1]?.typecheck(&schema).is_empty());
+    Ok(())
+}
+
 #[test]
 fn rule_match_string_op() -> Result<()> {
     assert!(rule!["=", ["lower", "Hi"], "hi"]?.matches(&json!({}))?);
     assert!(rule!["=", ["upper", "Hi"], "HI"]?.matches(&json!({}))?);
     assert!(rule!["match", "hello", "he*"]?.matches(&json!({}))?);
     assert!(rule!["regex", "hello", "^he[l-o]{3}$"]?.matches(&json!({}))?);
+
+    // a literal pattern is compiled into the expression, the others are compiled on first use
+    let r = rule!["&&", ["regex", "name", "^t.m$"], ["match", "name", ["var", "glob"]], ["regex", "name", ["var", "re"]]]?;
+    assert_eq!(Rule::new(r.to_json())?, r);
+    for (name, glob, re) in &[("tom", "t*", "o"), ("tim", "t?m", "^ti"), ("tam", "*m", "a")] {
+        for _ in 0..3 {
+            assert!(r.matches(&json!({"name": name, "glob": glob, "re": re}))?);
+        }
+        assert!(!r.matches(&json!({"name": name, "glob": "x*", "re": re}))?);
+    }
     Ok(())
 }
 
@@ -142,3 +295,808 @@ fn rule_match_casting_op() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn rule_match_datetime_op() -> Result<()> {
+    let options = Options { now: Some("2024-03-31T07:30:00Z".parse().unwrap()), ..Options::default() };
+
+    // account created more than 30 days ago
+    let r = rule!["<", ["datetime", "created_at"], ["dateadd", ["now"], "-30d"]]?.with_options(options.clone());
+    assert!(r.matches(&json!({"created_at": "2024-03-01T07:29:59Z"}))?);
+    assert!(!r.matches(&json!({"created_at": "2024-03-01T07:30:00Z"}))?);
+    assert!(r.matches(&json!({"created_at": 1_700_000_000}))?);
+
+    // only on weekdays between 9 and 17 in Europe/Berlin, the clock is set forward on 2024-03-31
+    let r = Rule::parse_expr("weekday(timezone(now(), 'Europe/Berlin')) <= 5 && hour(timezone(now(), 'Europe/Berlin')) in (9, 10, 11, 12, 13, 14, 15, 16)")?;
+    assert!(!r.clone().with_options(options.clone()).matches(&json!({}))?);
+    let monday = Options { now: Some("2024-04-01T07:30:00Z".parse().unwrap()), ..Options::default() };
+    assert!(r.clone().with_options(monday).matches(&json!({}))?);
+    let context = json!({"before": "2024-03-31T00:30:00Z", "after": "2024-03-31T01:30:00Z"});
+    assert!(rule!["=", ["hour", ["timezone", "before", "Europe/Berlin"]], 1]?.matches(&context)?);
+    assert!(rule!["=", ["hour", ["timezone", "after", "Europe/Berlin"]], 3]?.matches(&context)?);
+    assert!(rule!["=", ["hour", ["timezone", "after", "-05:30"]], 20]?.matches(&context)?);
+
+    // the same instant in different offsets, and durations of several units
+    assert!(rule!["=", ["datetime", "2024-03-31T09:30:00+02:00"], ["datetime", "2024-03-31T07:30:00Z"]]?.matches(&json!({}))?);
+    assert!(rule!["=", ["datediff", ["dateadd", "before", "1w2d3h4m5s6ms"], ["var", "before"]], 788_645.006]?.matches(&context)?);
+    assert!(rule!["=", ["datediff", ["dateadd", "before", 1.5], ["var", "before"]], 1.5]?.matches(&context)?);
+    assert!(rule!["=", ["minute", ["dateadd", "before", ["var", "step"]]], 0]?.matches(&json!({"before": "2024-03-31T00:30:00Z", "step": "30m"}))?);
+
+    // a datetime is written as `["datetime", ...]` in a rule, and as a string in a context
+    let val = Rule::new(json!(["dateadd", "2024-03-31", "1d"]))?.with_options(Options { strict: true, ..Options::default() }).expr().eval(&EvalCtx::new(&serde_json::Map::new()))?;
+    assert_eq!(val.to_json(), json!(["datetime", "2024-04-01T00:00:00Z"]));
+    assert_eq!(val.to_json_context_var(), json!("2024-04-01T00:00:00Z"));
+    assert_eq!(Rule::new(json!(["=", val.to_json(), ["datetime", "2024-04-01"]]))?.to_text(), "datetime('2024-04-01T00:00:00Z') = datetime('2024-04-01')");
+
+    // null stays null, so a missing variable doesn't match
+    let r = rule!["<", ["datetime", ["var", "deleted_at"]], ["now"]]?.with_options(Options { missing_var: MissingVar::Null, ..options });
+    assert_eq!(r.matches(&json!({}))?, false);
+    assert!(r.matches(&json!({"deleted_at": "2024-01-01"}))?);
+
+    // a literal duration or time zone is checked when the rule is built, the others when it's evaluated
+    assert!(matches!(rule!["dateadd", "ts", "30 days"], Err(Error::InvalidDuration { .. })));
+    assert!(matches!(rule!["timezone", "ts", "Europe/Nowhere"], Err(Error::InvalidTimeZone { .. })));
+    let err = Rule::parse_expr("a = 1 &&\n  hour(ts) > 9")?.matches(&json!({"a": 1, "ts": "noon"})).unwrap_err();
+    assert_eq!(err.to_string(), "invalid datetime `noon` in `hour` at args[1].args[0] (line 2, column 3)");
+    let err = rule!["dateadd", "ts", ["var", "step"]]?.matches(&json!({"ts": 0, "step": "1 day"})).unwrap_err();
+    assert_eq!(err.to_string(), "invalid duration `1 day` in `dateadd`");
+    assert!(matches!(rule!["datetime", "ts"]?.matches(&json!({"ts": true})), Err(Error::TypeError { .. })));
+    let err = rule!["<", ["now"], ["hour", "ts"]].unwrap_err();
+    assert_eq!(err.to_string(), "`<` expects args of one kind, got datetime and number");
+    Ok(())
+}
+
+
+fn is_vip(args: &[Arg], _ctx: &EvalCtx) -> Result<Arg> {
+    Ok(Arg::Bool(args[0] == Arg::String("vip".to_owned())))
+}
+
+#[test]
+fn rule_match_custom_op() -> Result<()> {
+    let mut registry = OpRegistry::new();
+    registry.register("is_vip", is_vip);
+    registry.alias("vip?", "is_vip")?;
+
+    let context = json!({"level": "vip", "a": 1});
+    assert!(Rule::with_registry(json!(["is_vip", "level"]), &registry)?.matches(&context)?);
+    assert!(Rule::with_registry(json!(["&", ["vip?", "level"], ["=", "a", 1]]), &registry)?.matches(&context)?);
+    assert!(Rule::new(json!(["is_vip", "level"])).is_err());
+    assert!(OpRegistry::empty().alias("vip?", "is_vip").is_err());
+    Ok(())
+}
+
+#[test]
+fn rule_match_closure_op() -> Result<()> {
+    let countries: HashMap<String, String> = vec![
+        ("1.1.1.1".to_owned(), "AU".to_owned()),
+        ("8.8.8.8".to_owned(), "US".to_owned()),
+    ].into_iter().collect();
+
+    let mut registry = OpRegistry::new();
+    registry.register("country_of_ip", move |args, _| {
+        Ok(countries.get(args[0].as_str().unwrap_or("")).map_or(Arg::Null, |c| Arg::String(c.clone())))
+    });
+
+    let rule = Rule::with_registry(json!(["=", ["country_of_ip", "ip"], "AU"]), &registry)?;
+    assert!(rule.matches(&json!({"ip": "1.1.1.1"}))?);
+    assert_eq!(rule.matches(&json!({"ip": "8.8.8.8"}))?, false);
+
+    // the registry and its closures can be shared across threads
+    let rule = std::thread::spawn(move || rule).join().unwrap();
+    assert!(rule.matches(&json!({"ip": "1.1.1.1"}))?);
+    Ok(())
+}
+
+#[test]
+fn rule_match_op_error() -> Result<()> {
+    assert!(matches!(rule!["regex", "hello", ["var", "re"]]?.matches(&json!({"re": "(he"})), Err(Error::InvalidRegex { .. })));
+    assert!(matches!(rule!["match", "hello", ["var", "p"]]?.matches(&json!({"p": "[he"})), Err(Error::InvalidGlob { .. })));
+    // literal patterns are compiled when the rule is built
+    assert!(matches!(rule!["regex", "hello", "(he"], Err(Error::InvalidRegex { .. })));
+    assert!(matches!(rule!["match", "hello", "[he"], Err(Error::InvalidGlob { .. })));
+    assert!(matches!(rule!["=", ["num", "abc"], 0]?.matches(&json!({})), Err(Error::TypeError { .. })));
+    // the number and kinds of args are checked when the rule is built
+    assert!(matches!(rule!["in"], Err(Error::SignatureError { .. })));
+    assert!(matches!(rule!["startswith", "hello"], Err(Error::SignatureError { .. })));
+    assert!(matches!(rule!["=", ["lower"], ""], Err(Error::SignatureError { .. })));
+    assert!(matches!(rule!["regex", "name", 1], Err(Error::SignatureError { .. })));
+    Ok(())
+}
+
+#[test]
+fn rule_match_short_circuit() -> Result<()> {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+
+    let mut registry = OpRegistry::new();
+    registry.register("count", move |_, _| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Ok(Arg::Bool(true))
+    });
+    // a lazy op only evaluates the branch taken
+    registry.register_lazy("if", |args, ctx| {
+        if ctx.eval(&args[0])?.as_bool().unwrap_or(false) { ctx.eval(&args[1]) } else { ctx.eval(&args[2]) }
+    });
+
+    let context = json!({"a": 1});
+    assert_eq!(Rule::with_registry(json!(["all", ["=", "a", 2], ["count"], ["count"]]), &registry)?.matches(&context)?, false);
+    assert!(Rule::with_registry(json!(["any", ["=", "a", 1], ["count"]]), &registry)?.matches(&context)?);
+    assert!(Rule::with_registry(json!(["if", ["=", "a", 1], true, ["count"]]), &registry)?.matches(&context)?);
+    assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+    assert!(Rule::with_registry(json!(["&&", ["=", "a", 1], ["count"]]), &registry)?.matches(&context)?);
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    // guards against errors in the later args
+    assert_eq!(rule!["&", ["=", "a", 2], ["regex", "a", ["lit", "("]]]?.matches(&context)?, false);
+    assert!(rule!["|", ["=", "a", 1], ["regex", "a", ["lit", "("]]]?.matches(&context)?);
+    Ok(())
+}
+
+#[test]
+fn rule_match_nested_var() -> Result<()> {
+    let context = json!({
+        "user": {"address": {"country": "DE"}},
+        "items": [{"sku": "A1"}, {"sku": "B2"}],
+        "a.b": {"c/d": 1},
+        "a~b": 2,
+    });
+    assert!(rule!["=", "user.address.country", "DE"]?.matches(&context)?);
+    assert!(rule!["=", ["var", "items.1.sku"], "B2"]?.matches(&context)?);
+    assert!(rule!["=", ["var", "/items/0/sku"], "A1"]?.matches(&context)?);
+    assert!(rule!["=", ["var", r"a\.b.c/d"], 1]?.matches(&context)?);
+    assert!(rule!["=", ["var", "/a.b/c~1d"], 1]?.matches(&context)?);
+    assert!(rule!["=", ["var", "/a~0b"], 2]?.matches(&context)?);
+    assert!(matches!(rule!["=", ["var", "items.2.sku"], "A1"]?.matches(&context), Err(Error::ContextNoSuchVarError { .. })));
+    assert!(matches!(rule!["=", ["var", "user.address.country.code"], "DE"]?.matches(&context), Err(Error::ContextNoSuchVarError { .. })));
+    // the first arg falls back to the literal string
+    assert!(rule!["=", "user.name", "user.name"]?.matches(&context)?);
+    Ok(())
+}
+
+#[test]
+fn rule_match_missing_var() -> Result<()> {
+    let context = json!({"a": 1});
+    assert!(rule!["=", ["var", "a", 2], 1]?.matches(&context)?);
+    assert!(rule!["=", ["var", "b", 2], 2]?.matches(&context)?);
+    assert!(rule!["=", ["var", "b", ["+", "a", 1]], 2]?.matches(&context)?);
+    assert!(matches!(rule!["=", ["var", "b"], 2]?.matches(&context), Err(Error::ContextNoSuchVarError { .. })));
+
+    let null = Options { missing_var: MissingVar::Null, ..Options::default() };
+    assert!(rule!["=", ["var", "b"], null]?.with_options(null.clone()).matches(&context)?);
+    assert!(rule!["=", ["var", "b", 2], 2]?.with_options(null).matches(&context)?);
+
+    let default = Options { missing_var: MissingVar::Default(Arg::Int(0)), ..Options::default() };
+    assert!(rule!["=", ["var", "b"], 0]?.with_options(default.clone()).matches(&context)?);
+    assert!(rule!["=", ["var", "a"], 1]?.with_options(default).matches(&context)?);
+    Ok(())
+}
+
+#[test]
+fn rule_match_literal() -> Result<()> {
+    let context = json!({"status": "active"});
+    assert!(rule!["=", "status", "active"]?.matches(&context)?);
+    assert!(rule!["=", ["lit", "status"], "status"]?.matches(&context)?);
+    assert!(rule!["=", ["quote", "status"], ["lit", "status"]]?.matches(&context)?);
+    assert!(rule!["in", ["lit", "status"], "status", "active"]?.matches(&context)?);
+    assert!(rule!["endswith", ["lit", [1, 2, 3]], 2, 3]?.matches(&context)?);
+    assert!(rule!["=", ["lit", null], null]?.matches(&context)?);
+
+    let strict = Options { strict: true, ..Options::default() };
+    assert_eq!(rule!["=", "status", "active"]?.with_options(strict.clone()).matches(&context)?, false);
+    assert!(rule!["=", "status", "status"]?.with_options(strict.clone()).matches(&context)?);
+    assert!(rule!["=", ["var", "status"], "active"]?.with_options(strict.clone()).matches(&context)?);
+    assert!(rule!["&", ["=", ["var", "status"], "active"], ["!", ["=", "status", "active"]]]?.with_options(strict).matches(&context)?);
+    Ok(())
+}
+
+#[test]
+fn rule_match_object() -> Result<()> {
+    let context = json!({"user": {"name": "tom", "tags": ["a", "b"]}, "a": 1});
+    assert!(rule!["=", "user", {"name": "tom", "tags": ["split", "a,b", ","]}]?.matches(&context)?);
+    assert!(rule!["=", ["get", "user", "name"], "tom"]?.matches(&context)?);
+    assert!(rule!["=", ["get", {"n": ["+", "a", 1]}, "n"], 2]?.matches(&context)?);
+    assert!(rule!["=", ["keys", "user"], ["split", "name,tags", ","]]?.matches(&context)?);
+    assert!(rule!["=", ["keys", ["lit", {"b": 1, "a": 2}]], ["split", "a,b", ","]]?.matches(&context)?);
+    assert!(rule!["has", "user", "tags"]?.matches(&context)?);
+    assert!(rule!["has", "user.tags.1"]?.matches(&context)?);
+    assert_eq!(rule!["has", "user.age"]?.matches(&context)?, false);
+    assert!(matches!(rule!["keys", "a"]?.matches(&context), Err(Error::TypeError { .. })));
+
+    // converting Json reports a bad expression in an object instead of keeping it as data
+    assert!(matches!(Arg::try_from(json!({"a": ["nope", 1]})), Err(Error::NoSuchOpError { .. })));
+    assert_eq!(Arg::try_from(json!({"a": ["lit", [1]]}))?.to_json(), json!({"a": ["lit", [1]]}));
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Config {
+    name: String,
+    rule: Rule,
+}
+
+#[test]
+fn rule_serde() -> Result<()> {
+    let rules = vec![
+        json!(["=", "a", 1]),
+        json!(["=", ["var", "a", 2], 1.5]),
+        json!(["&", ["=", "user", {"name": "tom", "n": ["+", 1, 2]}], ["!", null]]),
+        json!(["in", ["lit", "x"], "x", "y"]),
+        json!(["startswith", ["lit", [1, [2], {"a": [3]}]], 1]),
+        json!(["regex", "hello", "^he[l-o]*$"]),
+    ];
+    for val in rules {
+        let r = Rule::new(val.clone())?;
+        assert_eq!(r.to_json(), val);
+        assert_eq!(Rule::new(r.to_json())?, r);
+        assert_eq!(serde_json::to_value(&r)?, val);
+    }
+
+    let config: Config = serde_json::from_str(r#"{"name": "adult", "rule": [">=", "age", 18]}"#)?;
+    assert_eq!(config, Config { name: "adult".to_owned(), rule: rule![">=", "age", 18]? });
+    assert!(config.rule.matches(&json!({"age": 20}))?);
+    assert_eq!(serde_json::to_value(&config)?, json!({"name": "adult", "rule": [">=", "age", 18]}));
+    assert!(serde_json::from_str::<Config>(r#"{"name": "bad", "rule": ["nope", 1]}"#).is_err());
+
+    assert_eq!(serde_json::to_value(Arg::Array(vec![Arg::Int(1)]))?, json!(["lit", [1]]));
+    Ok(())
+}
+
+#[test]
+fn rule_parse_expr() -> Result<()> {
+    let context = json!({"age": 20, "country": "DE", "email": "Tom@x.com", "items": [{"sku": "A1"}]});
+    let cases = vec![
+        ("age > 18 && country in ('DE', 'FR')", json!(["&&", [">", ["var", "age"], 18], ["in", ["var", "country"], "DE", "FR"]])),
+        ("a + b * 2 - -3", json!(["-", ["+", ["var", "a"], ["*", ["var", "b"], 2]], -3])),
+        ("(a || b) && !c", json!(["&&", ["||", ["var", "a"], ["var", "b"]], ["!", ["var", "c"]]])),
+        ("1 < x < 10", json!(["<", 1, ["var", "x"], 10])),
+        ("x == 1.5", json!(["=", ["var", "x"], 1.5])),
+        ("-x", json!(["neg", ["var", "x"]])),
+        ("startswith(lower(email), 'tom')", json!(["startswith", ["lower", ["var", "email"]], "tom"])),
+        ("items.0.sku = \"A1\"", json!(["=", ["var", "items.0.sku"], "A1"])),
+        ("get({'a': 1, b: lit([1])}, 'a') = null", json!(["=", ["get", {"a": 1, "b": ["lit", [1]]}, "a"], null])),
+        ("endswith(lit([1, 'x', {'a': -1}]), 'x', {'a': -1})", json!(["endswith", ["lit", [1, "x", {"a": -1}]], "x", {"a": -1}])),
+        ("true", json!(["lit", true])),
+    ];
+    for (s, val) in cases {
+        let r = Rule::parse_expr(s)?;
+        assert_eq!(r, Rule::new(val)?, "{}", s);
+        assert_eq!(Rule::parse_expr(&r.to_text())?, r, "{}", s);
+    }
+    assert!(Rule::parse_expr("age > 18 && country in ('DE', 'FR')")?.matches(&context)?);
+    assert!(Rule::parse_expr("startswith(lower(email), 'tom') && items.0.sku = 'A1'")?.matches(&context)?);
+    Ok(())
+}
+
+#[test]
+fn rule_to_text() -> Result<()> {
+    let cases = vec![
+        (json!(["=", "a", 1]), "'a' = 1"),
+        (json!(["all", ["=", ["var", "a"], 1], ["any", true, false]]), "a = 1 && (true || false)"),
+        (json!(["-", ["-", 1, 2], 3]), "(1 - 2) - 3"),
+        (json!(["-", 1, ["-", 2, 3]]), "1 - (2 - 3)"),
+        (json!(["*", ["+", 1, 2], 3]), "(1 + 2) * 3"),
+        (json!(["+", 1]), "add(1)"),
+        (json!(["neg", 5]), "neg(5)"),
+        (json!(["!", ["=", 1, 2]]), "!(1 = 2)"),
+        (json!(["=", ["var", "a.b\\.c"], ["var", "/x/0"]]), "var('a.b\\\\.c') = var('/x/0')"),
+        (json!(["in", ["var", "in"], ["split", "a,b", ","]]), "var('in') in (split('a,b', ','))"),
+        (json!(["=", ["lit", "it's"], "it's"]), "lit('it\\'s') = 'it\\'s'"),
+        (json!(["=", "a", i64::MIN]), "'a' = -9223372036854775808"),
+        (json!(["=", ["lit", [i64::MIN, i64::MAX]], "a"]), "lit([-9223372036854775808, 9223372036854775807]) = 'a'"),
+    ];
+    for (val, s) in cases {
+        let r = Rule::new(val)?;
+        assert_eq!(r.to_text(), s);
+        assert_eq!(Rule::parse_expr(s)?.to_text(), s);
+    }
+    Ok(())
+}
+
+#[test]
+fn rule_parse_expr_error() {
+    let cases = vec![
+        ("a >", 1, 4, "expected an expression, found end of input"),
+        ("a > 1\n  && b <", 2, 9, "expected an expression, found end of input"),
+        ("a < b <= c", 1, 7, "comparison operators cannot be chained with each other, use parentheses"),
+        ("nope(1)", 1, 1, "no such operator `nope`"),
+        ("a in 1", 1, 3, "expected a parenthesized list after `in`"),
+        ("'abc", 1, 1, "unterminated string"),
+        ("a # b", 1, 3, "unexpected character `#`"),
+        ("(a", 1, 3, "expected `)`, found end of input"),
+        ("[1]", 1, 1, "arrays are data, use `lit([...])`"),
+        ("lit(a)", 1, 5, "expected a literal, found `a`"),
+        ("a b", 1, 3, "expected an operator or end of input, found `b`"),
+        ("a = lower()", 1, 5, "`lower` takes 1 arg, got 0"),
+        ("regex(a)", 1, 1, "`regex` takes 2 args, got 1"),
+        ("a = 9223372036854775808", 1, 5, "invalid number `9223372036854775808`"),
+        ("a = -(9223372036854775808)", 1, 7, "invalid number `9223372036854775808`"),
+        ("lit([9223372036854775808])", 1, 6, "invalid number `9223372036854775808`"),
+        ("a = 18446744073709551616", 1, 5, "invalid number `18446744073709551616`"),
+    ];
+    for (s, line, column, message) in cases {
+        match Rule::parse_expr(s) {
+            Err(Error::SyntaxError { line: l, column: c, message: m }) => {
+                assert_eq!((l, c, m.as_str()), (line, column, message), "{}", s);
+            },
+            other => panic!("{}: {:?}", s, other),
+        }
+    }
+}
+
+#[test]
+fn rule_explain() -> Result<()> {
+    let context = json!({"a": 1, "user": {"age": 30}});
+
+    let r = Rule::parse_expr("a = 2 || var('user.age', 0) >= 18")?;
+    let trace = r.explain(&context)?;
+    assert_eq!(trace.op, "||");
+    assert_eq!(trace.result, Some(Arg::Bool(true)));
+    assert_eq!(trace.children.len(), 2);
+    assert_eq!(trace.children[0].result, Some(Arg::Bool(false)));
+    // the default of `var` is lazy, and not evaluated when the variable exists
+    assert_eq!(trace.children[1].children[0].vars, vec![("user.age".to_owned(), Some(Arg::Int(30)))]);
+    assert_eq!(trace.to_string(), r#"||(false, true) => true
+  =(1, 2) => false
+    var("a") => 1
+      | a = 1
+  >=(30, 18) => true
+    var("user.age") => 30
+      | user.age = 30
+"#);
+
+    // short-circuited args are not evaluated, thus not traced
+    let trace = Rule::parse_expr("a = 1 || b = 1")?.explain(&context)?;
+    assert_eq!(trace.args, vec![Arg::Bool(true)]);
+    assert_eq!(trace.children.len(), 1);
+
+    // the first arg of a lazy operator resolved as a context variable is traced like the evaluated ones
+    let trace = rule!["&&", "flag", ["=", "a", 1]]?.explain(&json!({"flag": true, "a": 1}))?;
+    assert_eq!(trace.to_string(), r#"&&(true, true) => true
+  | flag = true
+  =(1, 1) => true
+    | a = 1
+"#);
+    let trace = rule!["some", "items", [">", "$", 1]]?.explain(&json!({"items": [1, 2]}))?;
+    assert_eq!(trace.args, vec![Arg::Array(vec![Arg::Int(1), Arg::Int(2)]), Arg::Bool(false), Arg::Bool(true)]);
+    assert_eq!(trace.vars, vec![("items".to_owned(), Some(Arg::Array(vec![Arg::Int(1), Arg::Int(2)])))]);
+
+    // errors are recorded in the trace instead of returned
+    let trace = Rule::parse_expr("a + var('missing') > 1")?.explain(&context)?;
+    assert_eq!(trace.result, None);
+    assert!(trace.error.is_some());
+    let var = &trace.children[0].children[1];
+    assert_eq!(var.vars, vec![("missing".to_owned(), None)]);
+    assert!(var.error.is_some());
+    assert_eq!(trace.to_json()["children"][0]["children"][1]["vars"], json!([{"name": "missing"}]));
+
+    assert!(matches!(r.explain(&json!([1])), Err(Error::ContextNotDictError)));
+    Ok(())
+}
+
+#[test]
+fn rule_error_location() -> Result<()> {
+    let err = Rule::new(json!(["&&", ["=", "a", 1], ["in", "b", ["nope", 1]]])).unwrap_err();
+    assert!(matches!(&err, Error::NoSuchOpError { op, .. } if op == "nope"));
+    assert_eq!(err.path().map(ToString::to_string), Some("args[1].args[1]".to_owned()));
+    assert_eq!(err.to_string(), "no such operator `nope` at args[1].args[1]");
+
+    let err = rule!["&&", true, ["=", ["var", "x"], 1]]?.matches(&json!({})).unwrap_err();
+    assert!(matches!(&err, Error::ContextNoSuchVarError { var, .. } if var == "x"));
+    assert_eq!(err.to_string(), "no such context variable `x` in `var` at args[1].args[0]");
+
+    // the span of the failing expression is known if parsed from the text syntax
+    let r = Rule::parse_expr("a = 1 && num(b) > 2")?;
+    let err = r.matches(&json!({"a": 1, "b": "x"})).unwrap_err();
+    let at = err.location().unwrap();
+    assert_eq!(at.op, "num");
+    assert_eq!(at.path.0, vec![1, 0]);
+    assert_eq!(at.span.map(|span| (span.start, span.end)), Some((9, 15)));
+    assert_eq!(err.to_string(), "invalid argument type in `num` at args[1].args[0] (line 1, column 10)");
+
+    let err = Rule::parse_expr("a = 1\n  && num(b) = 2")?.matches(&json!({"a": 1, "b": "x"})).unwrap_err();
+    assert_eq!(err.to_string(), "invalid argument type in `num` at args[1].args[0] (line 2, column 6)");
+
+    let err = rule!["&&", true, ["=", ["lower"], "a"]].unwrap_err();
+    assert_eq!(err.to_string(), "`lower` takes 1 arg, got 0 at args[1].args[0]");
+
+    let err = rule!["&&", true, ["regex", "hello", "(he"]].unwrap_err();
+    assert!(std::error::Error::source(&err).is_some());
+    assert!(err.to_string().starts_with("invalid regex in `regex` at args[1]: "));
+
+    let err = Rule::parse_expr("a = 1 &&\n  regex(b, '(he')").unwrap_err();
+    assert!(err.to_string().starts_with("invalid regex in `regex` (line 2, column 3): "));
+
+    let err = serde_json::from_value::<Rule>(json!(["=", ["nope"], 1])).unwrap_err();
+    assert_eq!(err.to_string(), "no such operator `nope` at args[0]");
+    Ok(())
+}
+
+#[test]
+fn op_signature() {
+    use rule::op::{Arity, Kind, Signature};
+
+    let registry = OpRegistry::builtin();
+    assert_eq!(registry.get("lower").unwrap().signature.arity, Arity::exact(1));
+    assert_eq!(registry.get("var").unwrap().signature.arity, Arity::range(1, 2));
+    assert_eq!(registry.get("and").unwrap().signature, registry.get("&&").unwrap().signature);
+    assert!(registry.get("in").unwrap().signature.arity.accepts(100));
+    assert!(!registry.get("regex").unwrap().signature.arity.accepts(1));
+    assert_eq!(registry.get("split").unwrap().signature.ret, Kind::ARRAY);
+    assert_eq!(registry.get("get").unwrap().signature.arg_kind(0), Kind::OBJECT | Kind::ARRAY);
+    assert_eq!(registry.get("join").unwrap().signature.arg_kind(5), Kind::ANY);
+    assert_eq!(OpRegistry::new().register("custom", |_, _| Ok(Arg::Null)).get("custom").unwrap().signature, Signature::any());
+    assert_eq!(Arity::at_least(2).to_string(), "at least 2 args");
+    assert!(registry.names().any(|name| name == "startswith"));
+    // the built-ins know their canonical name under every alias, the Rust names aren't op names
+    assert_eq!(registry.get("&&").unwrap().builtin, Some("and"));
+    assert_eq!(registry.get("let").unwrap().builtin, Some("let"));
+    assert_eq!(registry.get("in").unwrap().builtin, Some("in"));
+    assert!(!registry.names().any(|name| name.starts_with("r#")));
+
+    let err = |rule: Result<Rule>| rule.unwrap_err().to_string();
+    assert_eq!(err(rule!["regex", "name"]), "`regex` takes 2 args, got 1");
+    assert_eq!(err(rule!["=", 1, ["lower", 1]]), "`lower` expects string as args[0], got number at args[1]");
+    assert_eq!(err(rule!["=", ["keys", ["split", "a,b", ","]], 1]), "`keys` expects object as args[0], got array at args[0]");
+    assert_eq!(err(rule!["<", 1, "x"]), "`<` expects args of one kind, got number and string");
+    assert_eq!(err(rule!["+", 1, ["split", "a", ","]]), "`+` expects null, bool, number or string as args[1], got array");
+    assert_eq!(err(Rule::parse_expr("lower(1) = 'a'")), "syntax error at line 1, column 1: `lower` expects string as args[0], got number");
+    // the first arg may be a context variable of any kind, and so may be an expression returning any
+    assert!(rule!["keys", "user"].is_ok());
+    assert!(rule!["lower", ["get", "user", "name"]].is_ok());
+    // custom operators take anything by default
+    let mut registry = OpRegistry::new();
+    registry.register("custom", |_, _| Ok(Arg::Null));
+    assert!(Rule::with_registry(json!(["custom", 1, "x", {"a": null}]), &registry).is_ok());
+}
+
+#[test]
+fn rule_typecheck() -> Result<()> {
+    use rule::op::Kind;
+    use rule::types::Schema;
+
+    let schema = Schema::new()
+        .var("name", Kind::STRING)
+        .var("age", Kind::NUMBER)
+        .var("tags", Kind::ARRAY)
+        .var("user", Kind::OBJECT);
+    let messages = |r: Rule| r.typecheck(&schema).iter().map(ToString::to_string).collect::<Vec<_>>();
+
+    assert!(messages(rule!["&&", [">=", "age", 18], ["in", "name", "tom", "ann"], ["has", "user", "id"]]?).is_empty());
+    assert!(messages(rule!["=", ["lower", ["var", "name"]], "tom"]?).is_empty());
+    assert!(messages(rule!["startswith", "tags", "a"]?).is_empty());
+    // unknown variables and the expressions returning any are not conflicts
+    assert!(messages(rule!["=", ["+", "score", 1], ["get", "user", "n"]]?).is_empty());
+    assert!(Rule::new(json!(["=", ["+", "name", 1], 2]))?.typecheck(&Schema::new()).is_empty());
+
+    assert_eq!(messages(rule!["=", ["+", "name", 1], 2]?), vec!["`+` expects args of one kind, got string and number in `+` at args[0]"]);
+    assert_eq!(messages(rule!["&&", true, ["=", ["keys", "tags"], 1]]?), vec!["`keys` expects object as args[0], got array in `keys` at args[1].args[0]"]);
+    assert_eq!(messages(rule!["<", ["var", "name"], ["var", "age"]]?), vec!["`<` expects args of one kind, got string and number in `<`"]);
+    assert_eq!(messages(rule!["lower", "name"]?), vec!["the rule returns string, not bool in `lower`"]);
+    assert_eq!(messages(rule!["var", "age"]?), vec!["the rule returns number, not bool in `var`"]);
+    // a default value widens the kind of a variable
+    assert!(messages(rule!["var", "age", false]?).is_empty());
+    // in strict mode, a literal string first arg is a string
+    let r = rule!["=", ["keys", "user"], 1]?;
+    assert!(r.typecheck(&schema).is_empty());
+    let r = r.with_options(rule::ctx::Options { strict: true, ..Default::default() });
+    assert_eq!(messages(r), vec!["`keys` expects object as args[0], got string in `keys` at args[0]"]);
+
+    let diagnostics = Rule::parse_expr("age > 18 &&\n  name + 1 = 2")?.typecheck(&schema);
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].at.path.0, vec![1, 0]);
+    assert_eq!(diagnostics[0].at.span.map(|span| (span.line, span.column)), Some((2, 3)));
+    Ok(())
+}
+
+#[test]
+fn rule_variables() -> Result<()> {
+    let vars = |r: Rule| r.variables().into_iter().collect::<Vec<_>>();
+
+    assert_eq!(vars(rule!["=", "a", 1]?), vec!["a"]);
+    assert_eq!(vars(rule!["&&", ["has", "user.id"], ["has", "user", "name"], ["in", "b", "a", "c"]]?), vec!["b", "user", "user.id"]);
+    assert_eq!(vars(rule!["=", ["get", {"n": ["var", "x", ["var", "y"]]}, "n"], ["lit", "z"]]?), vec!["x", "y"]);
+    assert_eq!(vars(rule!["=", ["var", ["lower", "key"]], 1]?), vec!["key"]);
+    assert!(vars(rule!["=", 1, 1]?).is_empty());
+    assert_eq!(vars(Rule::parse_expr("age > 18 && startswith(lower(email), 'tom')")?), vec!["age", "email"]);
+
+    // in strict mode only the names of `var` and `has` are variables
+    let r = rule!["&&", ["=", "a", ["var", "b"]], ["has", "c"]]?;
+    assert_eq!(vars(r.clone()), vec!["a", "b", "c"]);
+    assert_eq!(vars(r.with_options(Options { strict: true, ..Options::default() })), vec!["b", "c"]);
+    assert_eq!(rule!["=", "a", ["var", "b"]]?.expr().variables().len(), 2);
+    Ok(())
+}
+
+#[test]
+fn rule_match_numeric_comparison() -> Result<()> {
+    // a context field which is sometimes an int and sometimes a float
+    for context in &[json!({"price": 10}), json!({"price": 10.0})] {
+        assert!(rule!["=", "price", 10]?.matches(context)?);
+        assert!(rule!["=", "price", 10.0]?.matches(context)?);
+        assert!(rule!["<", "price", 10.5]?.matches(context)?);
+        assert!(rule![">", "price", 9.99]?.matches(context)?);
+        assert!(rule!["<=", 10, ["var", "price"], 10.0]?.matches(context)?);
+        assert!(rule!["in", "price", 1, 10, 100]?.matches(context)?);
+        assert!(!rule!["!=", "price", 10]?.matches(context)?);
+    }
+    // ints beyond the precision of a float
+    assert!(rule!["<", 9007199254740993i64, 9007199254740994.0]?.matches(&json!({}))?);
+    assert!(rule![">", 9007199254740993i64, 9007199254740992.0]?.matches(&json!({}))?);
+    assert!(rule!["<", i64::MAX, 1e19]?.matches(&json!({}))?);
+
+    assert!(rule!["=", ["lit", [1, {"a": 2}]], ["lit", [1.0, {"a": 2.0}]]]?.matches(&json!({}))?);
+    assert!(rule!["<", ["lit", [1, 2]], ["lit", [1, 2.5]], ["lit", [1, 2.5, 0]]]?.matches(&json!({}))?);
+    assert!(rule!["<", false, true]?.matches(&json!({}))?);
+
+    // null and NaN are unordered, other types can't be ordered with each other
+    let nan = json!(["/", 0.0, 0.0]);
+    assert!(!rule!["<", null, 1]?.matches(&json!({}))?);
+    assert!(!rule![">=", null, 1]?.matches(&json!({}))?);
+    assert!(rule!["<=", null, null]?.matches(&json!({}))?);
+    assert!(!rule!["<", nan, 1]?.matches(&json!({}))?);
+    assert!(!rule!["=", nan, nan]?.matches(&json!({}))?);
+    assert!(!rule!["=", "1", 1]?.matches(&json!({}))?);
+    assert!(matches!(rule!["<", "a", 1]?.matches(&json!({"a": "1"})), Err(Error::TypeError { .. })));
+    assert!(matches!(rule![">", ["lit", {}], ["lit", {}]]?.matches(&json!({})), Err(Error::TypeError { .. })));
+    Ok(())
+}
+
+#[test]
+fn rule_match_checked_arithmetic() -> Result<()> {
+    let context = json!({"max": i64::MAX, "min": i64::MIN});
+    assert!(rule!["=", ["+", "max", 1], null]?.matches(&context)?);
+    assert!(rule!["=", ["-", "min", 1], null]?.matches(&context)?);
+    assert!(rule!["=", ["*", "max", 2], null]?.matches(&context)?);
+    assert!(rule!["=", ["/", 1, 0], null]?.matches(&context)?);
+    assert!(rule!["=", ["%", 1, 0], null]?.matches(&context)?);
+    assert!(rule!["=", ["/", "min", -1], null]?.matches(&context)?);
+    assert!(rule!["=", ["neg", "min"], null]?.matches(&context)?);
+    assert!(rule!["=", ["abs", "min"], null]?.matches(&context)?);
+    assert!(rule!["=", ["/", 1.0, 0], ["/", 2.0, 0]]?.matches(&context)?);
+    Ok(())
+}
+
+/// A xorshift PRNG, seeded so that a failure is reproducible.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+}
+
+/// The roots of the variable names in the random rules, the keys of the random contexts.
+const RANDOM_VARS: [&str; 12] = ["a", "f", "s", "max", "min", "zero", "arr", "user", "n", "nope", "$", "$acc"];
+
+fn random_scalar(rng: &mut Rng) -> serde_json::Value {
+    match rng.below(5) {
+        0 => json!(null),
+        1 => json!(rng.below(2) == 0),
+        2 => json!(*rng.pick(&[0, 1, -1, 2, 10, i64::MAX, i64::MIN, i64::MAX - 1])),
+        3 => json!(*rng.pick(&[0.0, -0.0, 0.5, -1.5, 1e308, -1e308, f64::MIN_POSITIVE])),
+        _ => json!(*rng.pick(&["", "a", "10", "1.5", "x y", "(", "[a-", "*", "\\", "ünï", "a,b", "%"])),
+    }
+}
+
+/// A random Json value of any kind, with nested arrays and objects of the keys the variable paths use.
+fn random_value(rng: &mut Rng, depth: usize) -> serde_json::Value {
+    match rng.below(if depth > 2 { 5 } else { 7 }) {
+        5 => serde_json::Value::Array((0..rng.below(4)).map(|_| random_value(rng, depth + 1)).collect()),
+        6 => serde_json::Value::Object((0..rng.below(4))
+            .map(|_| (rng.pick(&["name", "k", "0", "1", "a"]).to_string(), random_value(rng, depth + 1)))
+            .collect()),
+        _ => random_scalar(rng),
+    }
+}
+
+/// A random context, some of the variables are missing.
+fn random_context(rng: &mut Rng) -> serde_json::Value {
+    serde_json::Value::Object(RANDOM_VARS.iter()
+        .filter_map(|name| match rng.below(3) {
+            0 => None,
+            _ => Some((name.to_string(), random_value(rng, 0))),
+        })
+        .collect())
+}
+
+fn random_arg(rng: &mut Rng, ops: &[&str], depth: usize) -> serde_json::Value {
+    match rng.below(if depth > 3 { 7 } else { 10 }) {
+        0..=4 => random_scalar(rng),
+        5 => match rng.below(3) {
+            0 => json!(*rng.pick(&["user.name", "/arr/1", "$.k", "arr.0", "user.k.name"])),
+            _ => json!(*rng.pick(&RANDOM_VARS)),
+        },
+        6 => json!(["lit", [1, "x", null, [2.5]]]),
+        7 => json!({"k": random_arg(rng, ops, depth + 1)}),
+        _ => random_expr(rng, ops, depth + 1),
+    }
+}
+
+fn random_expr(rng: &mut Rng, ops: &[&str], depth: usize) -> serde_json::Value {
+    let mut expr = vec![json!(*rng.pick(ops))];
+    for _ in 0..rng.below(5) {
+        expr.push(random_arg(rng, ops, depth));
+    }
+    serde_json::Value::Array(expr)
+}
+
+#[test]
+fn rule_fuzz_no_panic() {
+    let context = json!({
+        "a": 1, "f": 1.5, "s": "hello", "max": i64::MAX, "min": i64::MIN, "zero": 0,
+        "arr": [1, "x", null], "user": {"name": "tom"}, "n": null,
+    });
+    let mut ops: Vec<&str> = OpRegistry::builtin().names().collect();
+    ops.sort_unstable();
+    ops.extend(&["nope", ""]);
+
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    for _ in 0..20000 {
+        let mut s = random_expr(&mut rng, &ops, 0).to_string();
+        // mutate some of them into (mostly) malformed Json
+        if rng.below(4) == 0 && !s.is_empty() {
+            let i = rng.below(s.len());
+            if s.is_char_boundary(i) {
+                s.insert(i, *rng.pick(&['[', ']', ',', '"', '1', '{']));
+            }
+        }
+        if let Ok(r) = Rule::from_str(&s) {
+            let _ = r.matches(&context);
+            let _ = r.explain(&context);
+            let _ = Rule::parse_expr(&r.to_text()).map(|r| r.matches(&context));
+        }
+    }
+
+    // random text syntax
+    let tokens = ["a", "s", "1", "-", "!", "+", "*", "/", "%", "(", ")", ",", "=", "<", "&&", "||", "in", "'x'", "lower", "lit", "[", "]", "{", "}", ":", "9223372036854775807"];
+    for _ in 0..20000 {
+        let s: Vec<&str> = (0..rng.below(12)).map(|_| *rng.pick(&tokens)).collect();
+        if let Ok(r) = Rule::parse_expr(&s.join(" ")) {
+            let _ = r.matches(&context);
+        }
+    }
+
+    // deeply nested input fails instead of overflowing the stack
+    assert!(Rule::parse_expr(&"(".repeat(100_000)).is_err());
+    assert!(Rule::parse_expr(&"!".repeat(100_000)).is_err());
+    assert!(Rule::parse_expr(&format!("lit({})", "[".repeat(100_000))).is_err());
+    assert!(Rule::from_str(&"[\"!\", ".repeat(100_000)).is_err());
+}
+
+/// Evaluates random rules with random options against random contexts, by the interpreter
+/// and by what `transform` makes of each rule, panics if the results differ, returns the number of comparisons.
+/// With `exact_errors`, the errors must be the same too, otherwise only both be errors.
+fn assert_same_results<F, E>(seed: u64, exact_errors: bool, mut transform: F) -> usize
+where
+    F: FnMut(&Rule) -> E,
+    E: Fn(&serde_json::Value) -> Result<Arg>,
+{
+    // a fixed clock, `now` would differ between the two evaluations
+    let now = Some("2024-05-06T10:00:00Z".parse().unwrap());
+    let options = [
+        Options { now, ..Options::default() },
+        Options { strict: true, now, ..Options::default() },
+        Options { missing_var: MissingVar::Null, now, ..Options::default() },
+        Options { missing_var: MissingVar::Default(Arg::Int(7)), now, ..Options::default() },
+        Options { strict: true, missing_var: MissingVar::Null, now },
+        Options { strict: true, missing_var: MissingVar::Default(Arg::Int(7)), now },
+    ];
+    let mut ops: Vec<&str> = OpRegistry::builtin().names().collect();
+    ops.sort_unstable();
+
+    let mut rng = Rng(seed);
+    let mut compared = 0;
+    for _ in 0..20000 {
+        let r = match Rule::new(random_expr(&mut rng, &ops, 0)) {
+            Ok(r) => r.with_options(rng.pick(&options).clone()),
+            Err(_) => continue,
+        };
+        let eval = transform(&r);
+        for context in &[random_context(&mut rng), random_context(&mut rng), random_context(&mut rng)] {
+            let ctx = EvalCtx::new(context.as_object().unwrap()).with_options(r.options());
+            let (expected, actual) = (r.expr().eval(&ctx), eval(context));
+            match (&expected, &actual) {
+                (Err(_), Err(_)) if !exact_errors => (),
+                _ => assert_eq!(format!("{:?}", actual), format!("{:?}", expected), "{}", r.to_json()),
+            }
+            compared += 1;
+        }
+    }
+    compared
+}
+
+#[test]
+fn rule_compile_same_results() -> Result<()> {
+    let compared = assert_same_results(0x9e37_79b9_7f4a_7c15, true, |r| {
+        let compiled = r.compile();
+        move |context| compiled.eval(context)
+    });
+    assert!(compared > 10000);
+
+    // the local variables of the compiled `let` and lambdas, also where the interpreter takes over
+    let mut registry = OpRegistry::new();
+    registry.register("get_x", |_, ctx| Ok(ctx.lookup("x").unwrap_or(Arg::Null)));
+    let context = json!({
+        "items": [{"sku": "A1", "price": 30, "qty": 2}, {"sku": "B7", "price": 120, "qty": 1}],
+        "tags": ["vip", "beta"], "x": "outer", "$": "not the element", "u": {"k": 1},
+    });
+    let rules = [
+        json!(["map", ["filter", "items", [">=", "$.qty", 2]], ["var", "$.sku"]]),
+        json!(["reduce", "items", ["+", ["var", "$acc"], ["*", "$.price", ["var", "$.qty"]]], 0]),
+        json!(["map", "items", ["some", "tags", ["=", ["var", "$"], ["lit", "vip"]]]]),
+        json!(["let", {"x": ["count", "tags"], "y": ["var", "x"]}, ["case", ["=", "x", 2], ["var", "y"], "no"]]),
+        json!(["let", {"x": 1}, ["let", {"x": ["+", ["var", "x"], 1]}, ["switch", "x", 1, "one", 2, "two"]]]),
+        json!(["let", {"x": 1}, ["get_x"]]),
+        json!(["let", {"x": 1}, ["var", ["lower", "X"]]]),
+        json!(["every", "items", ["has", "$.sku"]]),
+        json!(["some", "items", ["has", "$", "qty"]]),
+        json!(["map", "items", ["coalesce", ["var", "$.nope", null], "$.sku"]]),
+        json!(["map", "items", ["var", "$.nope"]]),
+        json!(["some", "tags", [">", "$", 1]]),
+        json!(["let", {"n": ["num", "x"]}, true]),
+        json!(["count", "u", true]),
+        json!(["if", ["in", "x", "outer", "inner"], ["not", "$"], ["now"]]),
+        // the variadic comparisons evaluate all the args, their errors come before the result
+        json!(["<", 1, 2, 3, ["count", "tags"]]),
+        json!(["<", 1, ["var", "x"], ["num", "x"]]),
+        json!(["=", 1, 2, ["num", "x"]]),
+        json!(["in", 1, 2, 1, ["num", "x"]]),
+        json!(["<", 3, 1, ["var", "x"]]),
+        json!(["<", 1, 3, ["var", "x"]]),
+        json!(["let", {"a": 1, "b": ["+", ["var", "x"], "!"], "c": ["lit", [1, 2]]}, ["reduce", "c", ["+", ["var", "$acc"], ["var", "$"], ["var", "a"]], 0]]),
+    ];
+    let options = Options { now: Some("2024-05-06T10:00:00Z".parse().unwrap()), ..Options::default() };
+    for rule in &rules {
+        let r = Rule::with_registry(rule.clone(), &registry)?.with_options(options.clone());
+        let expected = r.expr().eval(&EvalCtx::new(context.as_object().unwrap()).with_options(&options));
+        assert_eq!(format!("{:?}", r.compile().eval(&context)), format!("{:?}", expected), "{}", rule);
+    }
+
+    let r = Rule::parse_expr("a = 1 &&\n  num(s) > 1")?;
+    let err = r.compile().matches(&json!({"a": 1, "s": "x"})).unwrap_err();
+    assert_eq!(err.to_string(), "invalid argument type in `num` at args[1].args[0] (line 2, column 3)");
+    assert!(matches!(r.compile().matches(&json!([])), Err(Error::ContextNotDictError)));
+    assert!(matches!(rule!["+", 1]?.compile().matches(&json!({})), Err(Error::FinalResultNotBoolError)));
+    Ok(())
+}
+
+#[test]
+fn rule_optimize_same_results() -> Result<()> {
+    let mut smaller = 0;
+    let compared = assert_same_results(0x2545_f491_4f6c_dd1d, false, |r| {
+        // optimized in strict mode, the rule is equivalent with any options
+        let strict = Options { strict: true, ..r.options().clone() };
+        let optimized = r.clone().with_options(strict).optimize().with_options(r.options().clone());
+        assert_eq!(Rule::new(optimized.to_json()).unwrap().expr(), optimized.expr(), "{}", r.to_json());
+        if optimized.to_json().to_string().len() < r.to_json().to_string().len() {
+            smaller += 1;
+        }
+        move |context| {
+            let ctx = EvalCtx::new(context.as_object().unwrap()).with_options(optimized.options());
+            optimized.expr().eval(&ctx)
+        }
+    });
+    assert!(compared > 10000);
+    assert!(smaller > 1000);
+
+    // the first arg isn't folded in strict mode either, the optimized rule can be used without it
+    let strict = Options { strict: true, ..Options::default() };
+    let r = Rule::new(json!(["=", ["lower", "A"], "a"]))?.with_options(strict);
+    let optimized = r.optimize();
+    assert_eq!(optimized.to_json(), json!(["=", ["lower", "A"], "a"]));
+    assert!(optimized.matches(&json!({"A": "X"}))?);
+    let (r, optimized) = (r.with_options(Options::default()), optimized.with_options(Options::default()));
+    assert_eq!(optimized.matches(&json!({"A": "X"}))?, r.matches(&json!({"A": "X"}))?);
+    Ok(())
+}